@@ -1,11 +1,28 @@
 use std::io::{BufReader, BufWriter};
+use std::os::unix::fs::FileExt;
 use std::path::Path;
 use std::{fs::File, path::PathBuf};
 
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use fastdata_tfrecord::async_reader::io_uring_header_scan::{io_uring_scan_paths, ScanResult};
+use fastdata_tfrecord::async_reader::ReadOptions;
+use fastdata_tfrecord::constants::DEFAULT_MAX_RECORD_LENGTH;
+use fastdata_tfrecord::error::Result;
+use fastdata_tfrecord::indexing::format::{DataInfo, IndexFooter};
+use fastdata_tfrecord::indexing::global_index::GlobalIndex;
+use fastdata_tfrecord::indexing::key_index::{KeyIndex, KeyIndexBuilder, KeyKind};
+use fastdata_tfrecord::indexing::source::IndexSource;
+use fastdata_tfrecord::indexing::sync_reader::IndexReader;
 use fastdata_tfrecord::indexing::sync_writer::SyncIndexWriter;
+use fastdata_tfrecord::indexing::text::{open_text_index, write_text_index, IndexFormat};
+use fastdata_tfrecord::record::{parse_record, record_size};
 use fastdata_tfrecord::sync_reader::TfrecordReader;
-use rayon::prelude::{ParallelBridge, ParallelIterator};
+use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+
+/// Stop listing problems of one file after this many.
+const MAX_PROBLEMS: usize = 10;
+/// Files scanned at the same time with `--io-uring`.
+const SCAN_QUEUE_DEPTH: u32 = 64;
 
 #[derive(Debug, Parser)]
 struct Cli {
@@ -14,14 +31,53 @@ struct Cli {
 
     #[arg(long, short = 'j', default_value = "4")]
     num_threads: usize,
+
+    /// Index format used by make and check
+    #[arg(long, value_enum, default_value_t = Format::Binary, global = true)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    /// `<file>.tfrecord.idx` of fastdata
+    Binary,
+    /// `<file>.idx` text index of NVIDIA DALI
+    Dali,
+    /// `<file>.index` text index of the python tfrecord package
+    Tfrecord,
+}
+
+impl Format {
+    fn index_path(self, path: &Path) -> PathBuf {
+        match self {
+            Self::Binary => path.with_extension("tfrecord.idx"),
+            Self::Dali => path.with_extension("idx"),
+            Self::Tfrecord => path.with_extension("index"),
+        }
+    }
+
+    fn index_format(self) -> IndexFormat {
+        match self {
+            Self::Binary => IndexFormat::Binary,
+            Self::Dali | Self::Tfrecord => IndexFormat::Text,
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// Make index files
     Make(MakeArgs),
-    /// Check index files
-    Check {},
+    /// Count records from their headers
+    Count(CountArgs),
+    /// Check index files, exit with 1 if any index is bad (and not fixed)
+    Check(CheckArgs),
+    /// Make a dataset manifest from the index files of all shards
+    Manifest(ManifestArgs),
+    /// Convert an index between formats, the input format is detected
+    Convert(ConvertArgs),
+    /// Make a key to record index from a feature of every Example
+    Key(KeyArgs),
 }
 
 #[derive(Debug, Args)]
@@ -30,6 +86,84 @@ struct MakeArgs {
 
     #[arg(short, long, default_value = "*.tfrecord")]
     masks: String,
+
+    /// Read whole records and verify data crcs, default only reads headers
+    #[arg(long, conflicts_with = "io_uring")]
+    verify_data: bool,
+
+    /// Pipeline header reads of all files with io_uring
+    #[arg(long)]
+    io_uring: bool,
+}
+
+#[derive(Debug, Args)]
+struct CountArgs {
+    path: PathBuf,
+
+    #[arg(short, long, default_value = "*.tfrecord")]
+    masks: String,
+
+    /// Pipeline header reads of all files with io_uring
+    #[arg(long)]
+    io_uring: bool,
+}
+
+#[derive(Debug, Args)]
+struct ManifestArgs {
+    path: PathBuf,
+
+    #[arg(short, long, default_value = "*.tfrecord")]
+    masks: String,
+
+    /// Default is `<path>/dataset.idx`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct KeyArgs {
+    path: PathBuf,
+
+    #[arg(short, long, default_value = "*.tfrecord")]
+    masks: String,
+
+    /// Name of a bytes or int64 feature
+    #[arg(long)]
+    feature: String,
+
+    /// The feature is int64, default is bytes
+    #[arg(long)]
+    int64: bool,
+
+    /// Default is `<path>/<feature>.kidx`
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct ConvertArgs {
+    input: PathBuf,
+
+    output: PathBuf,
+
+    #[arg(long, value_enum)]
+    to: Format,
+
+    /// Data file, used for the footer of a binary index
+    #[arg(long)]
+    data: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+struct CheckArgs {
+    path: PathBuf,
+
+    #[arg(short, long, default_value = "*.tfrecord")]
+    masks: String,
+
+    /// Regenerate bad indexes
+    #[arg(long)]
+    fix: bool,
 }
 
 fn main() {
@@ -42,41 +176,311 @@ fn main() {
         .unwrap();
 
     match cli.command {
-        Commands::Make(ref args) => make_index_files(args),
-        Commands::Check {} => {}
+        Commands::Make(ref args) => make_index_files(args, cli.format),
+        Commands::Count(ref args) => count_records(args),
+        Commands::Convert(ref args) => convert_index(args).unwrap(),
+        Commands::Key(ref args) => {
+            let kind = if args.int64 {
+                KeyKind::Int64
+            } else {
+                KeyKind::Bytes
+            };
+            let mut paths = find_files(&args.path, &args.masks);
+            paths.sort();
+            let output = args
+                .output
+                .clone()
+                .unwrap_or_else(|| args.path.join(format!("{}.kidx", args.feature)));
+
+            let mut builder = KeyIndexBuilder::new(&args.feature, kind);
+            for path in &paths {
+                builder.add_shard(path).unwrap();
+            }
+            builder.write(&output).unwrap();
+            let index = KeyIndex::open(&output).unwrap();
+            println!("{}: {} keys", output.display(), index.len());
+        }
+        Commands::Check(ref args) => {
+            if !check_index_files(args, cli.format) {
+                std::process::exit(1);
+            }
+        }
+        Commands::Manifest(ref args) => {
+            let mut paths = find_files(&args.path, &args.masks);
+            paths.sort();
+            let output = args
+                .output
+                .clone()
+                .unwrap_or_else(|| args.path.join("dataset.idx"));
+            GlobalIndex::build(&output, &paths).unwrap();
+            let index = GlobalIndex::open(&output).unwrap();
+            println!(
+                "{}: {} shards, {} records",
+                output.display(),
+                index.num_shards(),
+                index.len()
+            );
+        }
     }
 }
 
-fn make_index_files(args: &MakeArgs) {
-    if args.path.is_dir() {
-        let pattern = args.path.join(&args.masks);
+fn find_files(path: &Path, masks: &str) -> Vec<PathBuf> {
+    if path.is_dir() {
+        let pattern = path.join(masks);
         glob::glob(pattern.to_str().unwrap())
             .unwrap()
-            .par_bridge()
-            .for_each(|path| {
-                let path = path.unwrap();
-                dbg!(&path);
-                create_index(&path);
-            });
-    } else if args.path.is_file() {
-        create_index(&args.path);
+            .map(|path| path.unwrap())
+            .collect()
+    } else {
+        vec![path.to_owned()]
+    }
+}
+
+fn make_index_files(args: &MakeArgs, format: Format) {
+    let paths = find_files(&args.path, &args.masks);
+    if args.io_uring {
+        for (path, entries) in paths.iter().zip(scan_headers(&paths).unwrap()) {
+            dbg!(path);
+            entries
+                .and_then(|entries| try_save_index(path, &entries, format))
+                .unwrap();
+        }
+    } else {
+        paths.par_iter().for_each(|path| {
+            dbg!(path);
+            create_index(path, format, args.verify_data);
+        });
+    }
+}
+
+fn count_records(args: &CountArgs) {
+    let paths = find_files(&args.path, &args.masks);
+    let counts: Vec<Result<usize>> = if args.io_uring {
+        scan_headers(&paths)
+            .unwrap()
+            .into_iter()
+            .map(|entries| entries.map(|entries| entries.len()))
+            .collect()
+    } else {
+        paths
+            .par_iter()
+            .map(|path| TfrecordReader::open(path, true)?.count_records())
+            .collect()
+    };
+
+    let mut total = 0;
+    for (path, count) in paths.iter().zip(counts) {
+        let count = count.unwrap();
+        println!("{}: {count} records", path.display());
+        total += count;
+    }
+    println!("total: {total} records in {} files", paths.len());
+}
+
+fn scan_headers(paths: &[PathBuf]) -> Result<Vec<ScanResult>> {
+    io_uring_scan_paths(paths, ReadOptions::new(SCAN_QUEUE_DEPTH, true))
+}
+
+fn create_index<P: AsRef<Path>>(path: P, format: Format, verify_data: bool) {
+    try_create_index(path, format, verify_data).unwrap();
+}
+
+fn try_create_index<P: AsRef<Path>>(path: P, format: Format, verify_data: bool) -> Result<()> {
+    let entries = read_entries(path.as_ref(), verify_data)?;
+    try_save_index(path.as_ref(), &entries, format)
+}
+
+/// Write to a temporary file first, so a failure doesn't clobber the old index.
+fn try_save_index(path: &Path, entries: &Vec<(u64, u64)>, format: Format) -> Result<()> {
+    let index_path = format.index_path(path);
+    let mut temp_path = index_path.clone().into_os_string();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    let data = DataInfo::from_metadata(&std::fs::metadata(path)?);
+    let result = save_index(&temp_path, entries, format.index_format(), data);
+    match result {
+        Ok(()) => std::fs::rename(&temp_path, &index_path)?,
+        Err(_) => {
+            let _ = std::fs::remove_file(&temp_path);
+        }
     }
+    result
 }
 
-fn create_index<P: AsRef<Path>>(path: P) {
-    let in_file = File::open(&path).unwrap();
-    // let index_path = format!("{}.idx", path.as_ref().to_str().unwrap());
-    let index_path = path.as_ref().to_owned().with_extension("tfrecord.idx");
-    let out_file = File::create(&index_path).unwrap();
+/// Index entries from headers only, or from whole records with `verify_data`.
+fn read_entries(path: &Path, verify_data: bool) -> Result<Vec<(u64, u64)>> {
+    let reader = TfrecordReader::new(BufReader::new(File::open(path)?), true);
+    if verify_data {
+        reader.verified_indices().collect()
+    } else {
+        reader.indices().collect()
+    }
+}
+
+fn save_index(
+    index_path: &Path,
+    entries: &Vec<(u64, u64)>,
+    format: IndexFormat,
+    data: DataInfo,
+) -> Result<()> {
+    let buf_writer = BufWriter::new(File::create(index_path)?);
+    match format {
+        IndexFormat::Binary => {
+            let mut index_writer = SyncIndexWriter::new(buf_writer);
+            for (offset, length) in entries.iter() {
+                index_writer.write_index(offset, length)?;
+            }
+            index_writer.write_footer(data)?;
+            index_writer.flush()
+        }
+        IndexFormat::Text => write_text_index(buf_writer, entries),
+    }
+}
+
+/// Entries and the footer of a binary index.
+type LoadedIndex = (Vec<(u64, u64)>, Option<IndexFooter>);
+
+fn load_index(index_path: &Path, format: IndexFormat) -> Result<LoadedIndex> {
+    match format {
+        IndexFormat::Binary => {
+            let index = IndexReader::open(index_path)?;
+            Ok((index.iter().collect(), index.footer().copied()))
+        }
+        IndexFormat::Text => Ok((open_text_index(index_path)?, None)),
+    }
+}
+
+fn convert_index(args: &ConvertArgs) -> Result<()> {
+    let (entries, _) = load_index(&args.input, IndexFormat::detect_file(&args.input)?)?;
+    let data = match &args.data {
+        Some(data) => DataInfo::from_metadata(&std::fs::metadata(data)?),
+        // mtime 0 is never checked, the size is where the last record ends
+        None => DataInfo {
+            size: entries
+                .iter()
+                .map(|(offset, length)| offset + length)
+                .max()
+                .unwrap_or(0),
+            ..Default::default()
+        },
+    };
+    save_index(&args.output, &entries, args.to.index_format(), data)?;
+    println!(
+        "{} -> {}: {} records",
+        args.input.display(),
+        args.output.display(),
+        entries.len()
+    );
+    Ok(())
+}
+
+/// Return true if every index is good or has been fixed.
+fn check_index_files(args: &CheckArgs, format: Format) -> bool {
+    let paths = find_files(&args.path, &args.masks);
+    let num_bad = paths
+        .par_iter()
+        .filter(|path| {
+            let problems = check_index(path, format);
+            if problems.is_empty() {
+                println!("ok {}", path.display());
+                return false;
+            }
 
-    let buf_reader = BufReader::new(in_file);
-    let reader = TfrecordReader::new(buf_reader, true);
+            let mut report = format!("bad {}\n", path.display());
+            for problem in problems.iter().take(MAX_PROBLEMS) {
+                report.push_str(&format!("  {problem}\n"));
+            }
+            if problems.len() > MAX_PROBLEMS {
+                report.push_str(&format!("  ... {} more\n", problems.len() - MAX_PROBLEMS));
+            }
 
-    let buf_writer = BufWriter::new(out_file);
-    let mut index_writer = SyncIndexWriter::new(buf_writer);
+            let fixed = args.fix
+                && match try_create_index(path, format, true) {
+                    Ok(()) => {
+                        report.push_str("  fixed\n");
+                        true
+                    }
+                    Err(err) => {
+                        report.push_str(&format!("  fix failed: {err}\n"));
+                        false
+                    }
+                };
+            print!("{report}");
+            !fixed
+        })
+        .count();
 
-    reader.indices().for_each(|index| {
-        let (offset, length) = index.unwrap();
-        index_writer.write_index(offset, length).unwrap();
-    });
+    println!("{} files, {} bad", paths.len(), num_bad);
+    num_bad == 0
+}
+
+/// Walk the index and check every entry against the data file.
+fn check_index(path: &Path, format: Format) -> Vec<String> {
+    let mut problems = Vec::new();
+    let index_path = format.index_path(path);
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) => return vec![format!("can't open data: {err}")],
+    };
+    let data_size = match file.metadata() {
+        Ok(metadata) => metadata.len(),
+        Err(err) => return vec![format!("can't stat data: {err}")],
+    };
+    let (index, footer) = match load_index(&index_path, format.index_format()) {
+        Ok(index) => index,
+        Err(err) => return vec![format!("can't open index: {err}")],
+    };
+
+    if let Some(footer) = footer {
+        let result = file
+            .metadata()
+            .map_err(Into::into)
+            .and_then(|metadata| footer.check_data(&metadata))
+            .and_then(|_| footer.verify_data_crc(BufReader::new(&file)));
+        if let Err(err) = result {
+            problems.push(err.to_string());
+        }
+    }
+
+    let mut buf = Vec::new();
+    let mut expect_offset = 0;
+    for (i, (offset, length)) in index.iter().enumerate() {
+        if offset > expect_offset {
+            problems.push(format!(
+                "entry {i}: gap of {} bytes before offset {offset}",
+                offset - expect_offset
+            ));
+        } else if offset < expect_offset {
+            problems.push(format!(
+                "entry {i}: offset {offset} overlaps the previous record ending at {expect_offset}"
+            ));
+        }
+        expect_offset = offset.saturating_add(length);
+
+        if length < record_size(0) || length > record_size(DEFAULT_MAX_RECORD_LENGTH) {
+            problems.push(format!("entry {i}: invalid length {length}"));
+            continue;
+        }
+        if expect_offset > data_size {
+            problems.push(format!(
+                "entry {i}: record at {offset}..{expect_offset} is past the end of data ({data_size})"
+            ));
+            continue;
+        }
+
+        buf.resize(length as usize, 0);
+        let result = file
+            .read_exact_at(&mut buf, offset)
+            .map_err(Into::into)
+            .and_then(|_| parse_record(&buf, offset, true));
+        if let Err(err) = result {
+            problems.push(format!("entry {i}: {err}"));
+        }
+    }
+
+    if expect_offset < data_size {
+        problems.push(format!("index covers {expect_offset} of {data_size} bytes"));
+    }
+    problems
 }
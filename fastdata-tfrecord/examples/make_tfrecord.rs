@@ -2,15 +2,11 @@ use std::{collections::HashMap, fs::File, io::Read, path::PathBuf};
 
 use clap::Parser;
 use fastdata_tfrecord::{
-    sync_writer::TfrecordWriter,
+    sharded_writer::ShardedTfrecordWriter,
     tensorflow::{Example, Feature},
 };
-use kanal::bounded;
 use prost::Message;
-use rayon::{
-    prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelBridge, ParallelIterator},
-    slice::ParallelSlice,
-};
+use rayon::prelude::{IntoParallelRefIterator, ParallelBridge, ParallelIterator};
 
 #[derive(Debug, Parser)]
 struct Cli {
@@ -20,6 +16,10 @@ struct Cli {
 
     #[arg(long, short = 'j', default_value = "4")]
     num_threads: usize,
+
+    /// Number of records per shard
+    #[arg(long, default_value = "500")]
+    shard_size: usize,
 }
 
 fn main() {
@@ -83,28 +83,25 @@ fn main() {
 
     dbg!(samples.len());
 
-    let num_chunks = samples.chunks(500).len();
-
-    samples
-        .par_chunks(500)
-        .enumerate()
-        .for_each(|(index, chunk)| {
-            let path = cli
-                .out_dir
-                .join(format!("{:06}-of-{:06}.tfrecord", index + 1, num_chunks));
-            let mut writer = TfrecordWriter::create(&path).unwrap();
-            for (img_path, label) in chunk {
-                let mut img_buf = Vec::new();
-                File::open(&img_path)
-                    .unwrap()
-                    .read_to_end(&mut img_buf)
-                    .unwrap();
-
-                let image_feat = Feature::from(img_buf);
-                let label_feat = Feature::from(vec![*label as i64]);
-                let example = Example::from([("image", image_feat), ("label", label_feat)]);
-                let example_buf = example.encode_to_vec();
-                writer.write(&example_buf).unwrap();
-            }
-        });
+    let mut writer =
+        ShardedTfrecordWriter::new(&cli.out_dir, "{shard:06}-of-{num_shards:06}.tfrecord").unwrap();
+    writer.set_max_records(Some(cli.shard_size));
+    writer.set_write_index(true);
+
+    samples.par_iter().for_each(|(img_path, label)| {
+        let mut img_buf = Vec::new();
+        File::open(img_path)
+            .unwrap()
+            .read_to_end(&mut img_buf)
+            .unwrap();
+
+        let image_feat = Feature::from(img_buf);
+        let label_feat = Feature::from(vec![*label as i64]);
+        let example = Example::from([("image", image_feat), ("label", label_feat)]);
+        let example_buf = example.encode_to_vec();
+        writer.write(&example_buf).unwrap();
+    });
+
+    let shards = writer.close().unwrap();
+    dbg!(shards.len());
 }
@@ -1,6 +1,6 @@
 use std::{
     io::BufReader,
-    path::PathBuf,
+    path::{Path, PathBuf},
     time::{Duration, Instant},
 };
 
@@ -9,7 +9,13 @@ use fastdata_tfrecord::{
     async_reader::{
         self,
         io_uring_random_reader::AsyncRandomReader,
+        io_uring_shuffled_reader::{RecordOrder, ShuffledReadOptions},
         io_uring_single_file::{AsyncBufReader, AsyncDepthOneTfrecordReader},
+        ReadOptions,
+    },
+    indexing::{
+        cache::{open_or_build_index, IndexCache},
+        source::IndexSource,
     },
     sync_reader::TfrecordReader,
 };
@@ -32,6 +38,33 @@ struct Cli {
 
     #[arg(value_enum)]
     reader: Reader,
+
+    /// Keep indexes built on open in this directory, default builds them in memory
+    #[arg(long)]
+    index_cache: Option<PathBuf>,
+
+    /// Read with `ReadFixed` into registered buffers of this size and fixed files,
+    /// for io-uring-multi-files and io-uring-indexed
+    #[arg(long)]
+    fixed_buffer_size: Option<usize>,
+
+    /// Read with O_DIRECT, bypassing the page cache, for io-uring-multi-files and
+    /// io-uring-indexed
+    #[arg(long, conflicts_with = "fixed_buffer_size")]
+    direct_io: bool,
+
+    /// Shards open at once for io-uring-shuffled
+    #[arg(long, default_value = "256")]
+    max_open_files: usize,
+
+    /// Pass records of io-uring-shuffled in the shuffled order instead of as they are read
+    #[arg(long)]
+    in_order: bool,
+
+    /// Pass records of io-uring-multi-files round robin across the files read at once,
+    /// the same order every run
+    #[arg(long)]
+    interleave: bool,
 }
 
 #[derive(Debug, Clone, Copy, ValueEnum)]
@@ -41,6 +74,7 @@ enum Reader {
     Sync,
     SyncOverAsync,
     IoUringIndexed,
+    IoUringShuffled,
 }
 
 fn main() {
@@ -62,6 +96,7 @@ fn main() {
         Reader::Sync => bench_sync(&cli, tfrecords),
         Reader::SyncOverAsync => bench_sync_over_async(&cli, tfrecords),
         Reader::IoUringIndexed => bench_io_uring_indexed(&cli, tfrecords),
+        Reader::IoUringShuffled => bench_io_uring_shuffled(&cli, tfrecords),
     };
 
     let secs = elapsed.as_secs_f64();
@@ -80,13 +115,14 @@ fn bench_io_uring_multi_files(cli: &Cli, tfrecords: Vec<PathBuf>) -> (usize, Dur
 
     let (sender, receiver) = bounded(cli.queue_depth as usize);
 
-    let queue_depth = cli.queue_depth;
-    let check_integrity = cli.check_integrity;
+    let mut options = ReadOptions::new(cli.queue_depth, cli.check_integrity);
+    options.fixed_buffer_size = cli.fixed_buffer_size;
+    options.direct_io = cli.direct_io;
+    options.interleave = cli.interleave;
     let _ = std::thread::spawn(move || {
-        async_reader::io_uring_multi_files::io_uring_loop(
+        async_reader::io_uring_multi_files::io_uring_loop_with_options(
             tfrecord_files,
-            queue_depth,
-            check_integrity,
+            options,
             |buf| sender.send(buf).unwrap(),
         )
         .expect("exit loop");
@@ -108,7 +144,9 @@ fn bench_io_uring_single_file_depth_one(cli: &Cli, tfrecords: Vec<PathBuf>) -> (
         .par_iter()
         .flat_map_iter(|path| {
             let file = std::fs::File::open(path).unwrap();
-            AsyncDepthOneTfrecordReader::new(file, cli.check_integrity).unwrap()
+            let mut reader = AsyncDepthOneTfrecordReader::new(file, cli.check_integrity).unwrap();
+            reader.set_direct_io(cli.direct_io).unwrap();
+            reader
         })
         .map(|buf| buf.unwrap())
         .count();
@@ -182,17 +220,22 @@ fn bench_io_uring_indexed(cli: &Cli, tfrecords: Vec<PathBuf>) -> (usize, Duratio
 
     let (sender, receiver) = bounded(1024 * 1024 * 1024);
 
-    let queue_depth = cli.queue_depth;
-    let check_integrity = cli.check_integrity;
+    let mut options = ReadOptions::new(cli.queue_depth, cli.check_integrity);
+    options.fixed_buffer_size = cli.fixed_buffer_size;
+    options.direct_io = cli.direct_io;
+    let cache = cli
+        .index_cache
+        .clone()
+        .map_or(IndexCache::Memory, IndexCache::Dir);
 
     std::thread::spawn(move || {
         tfrecords.par_iter().for_each_with(sender, |sender, path| {
             dbg!(path);
-            async_reader::io_uring_random_reader::io_uring_loop(
+            async_reader::io_uring_random_reader::io_uring_loop_with_cache(
                 path,
-                None,
-                queue_depth,
-                check_integrity,
+                None::<&Path>,
+                &cache,
+                options,
                 |buf| sender.send(buf).unwrap(),
             )
             .unwrap();
@@ -203,3 +246,55 @@ fn bench_io_uring_indexed(cli: &Cli, tfrecords: Vec<PathBuf>) -> (usize, Duratio
     let num_records = receiver.count();
     (num_records, start_time.elapsed())
 }
+
+fn bench_io_uring_shuffled(cli: &Cli, tfrecords: Vec<PathBuf>) -> (usize, Duration) {
+    let cache = cli
+        .index_cache
+        .clone()
+        .map_or(IndexCache::Memory, IndexCache::Dir);
+    let shards: Vec<_> = tfrecords
+        .into_iter()
+        .map(|path| {
+            let index = open_or_build_index(&path, None::<&Path>, &cache).unwrap();
+            (path, index)
+        })
+        .collect();
+
+    // every record once, in a fixed random order
+    let mut requests: Vec<(usize, usize)> = shards
+        .iter()
+        .enumerate()
+        .flat_map(|(shard, (_, index))| (0..index.len()).map(move |record| (shard, record)))
+        .collect();
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    for i in (1..requests.len()).rev() {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        requests.swap(i, state as usize % (i + 1));
+    }
+
+    let mut read_options = ReadOptions::new(cli.queue_depth, cli.check_integrity);
+    read_options.fixed_buffer_size = cli.fixed_buffer_size;
+    read_options.direct_io = cli.direct_io;
+    let mut options = ShuffledReadOptions::new(read_options);
+    options.max_open_files = cli.max_open_files;
+    if cli.in_order {
+        options.order = RecordOrder::Requested;
+    }
+
+    let (sender, receiver) = bounded(cli.queue_depth as usize);
+    std::thread::spawn(move || {
+        async_reader::io_uring_shuffled_reader::io_uring_loop_shuffled(
+            &shards,
+            requests,
+            options,
+            |_, buf| sender.send(buf).unwrap(),
+        )
+        .expect("exit loop");
+    });
+
+    let start_time = Instant::now();
+    let num_records = receiver.count();
+    (num_records, start_time.elapsed())
+}
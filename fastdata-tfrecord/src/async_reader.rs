@@ -1,3 +1,302 @@
+mod direct;
+pub mod io_uring_header_scan;
 pub mod io_uring_multi_files;
 pub mod io_uring_random_reader;
+pub mod io_uring_shuffled_reader;
 pub mod io_uring_single_file;
+
+use std::{io::ErrorKind, os::fd::RawFd, path::Path};
+
+use io_uring::{opcode, squeue, types, IoUring};
+
+use crate::{
+    constants::DEFAULT_MAX_RECORD_LENGTH,
+    crc32c::verify_masked_crc,
+    error::{Error, ReadContext, Result},
+    record::read_u32,
+    utils::IoVec,
+};
+
+/// Options shared by the io_uring readers.
+#[derive(Debug, Clone, Copy)]
+pub struct ReadOptions {
+    pub queue_depth: u32,
+    pub check_integrity: bool,
+    /// A header with a larger data length is treated as corrupted instead of allocating for it.
+    pub max_record_length: u64,
+    /// Register `queue_depth` buffers of this size and the open files with the ring,
+    /// reads that fit use `ReadFixed` on a fixed file and are copied out of the buffer.
+    /// The buffers are pinned and count against `RLIMIT_MEMLOCK`.
+    pub fixed_buffer_size: Option<usize>,
+    /// Open the files again with `O_DIRECT` and read them in aligned blocks, so reading
+    /// a dataset doesn't evict the page cache. Files passed in keep their flags. The
+    /// filesystem must support it, and it can't be used with `fixed_buffer_size`.
+    pub direct_io: bool,
+    /// Only for `io_uring_multi_files`: pass records in a fixed order instead of the
+    /// order reads complete in. File `n` is read in lane `n % queue_depth` and the lanes
+    /// pass one record each in turn, a lane reads at most a few records ahead, or the
+    /// records in its blocks with `direct_io`.
+    pub interleave: bool,
+}
+
+impl ReadOptions {
+    pub fn new(queue_depth: u32, check_integrity: bool) -> Self {
+        Self {
+            queue_depth,
+            check_integrity,
+            max_record_length: DEFAULT_MAX_RECORD_LENGTH,
+            fixed_buffer_size: None,
+            direct_io: false,
+            interleave: false,
+        }
+    }
+
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.direct_io && self.fixed_buffer_size.is_some() {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "direct_io can't be used with fixed_buffer_size",
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Copy `bytes` to `bufs` in order, until either runs out.
+pub(crate) fn scatter(mut bytes: &[u8], bufs: &mut [&mut [u8]]) {
+    for buf in bufs {
+        let len = buf.len().min(bytes.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        bytes = &bytes[len..];
+    }
+}
+
+/// Buffers and files registered with a ring, slot `i` of both belongs to the read
+/// with user data `i`.
+pub(crate) struct Registered {
+    arena: Box<[u8]>,
+    slot_size: usize,
+}
+
+impl Registered {
+    /// Register `slots` buffers of `slot_size` bytes and `files`, an fd of -1 leaves
+    /// the slot empty for [`Registered::set_file`].
+    pub fn new(ring: &IoUring, slots: usize, slot_size: usize, files: &[RawFd]) -> Result<Self> {
+        let mut arena = vec![0; slots * slot_size].into_boxed_slice();
+        let io_vecs: Vec<libc::iovec> = arena
+            .chunks_exact_mut(slot_size)
+            .map(|slot| libc::iovec {
+                iov_base: slot.as_mut_ptr().cast(),
+                iov_len: slot.len(),
+            })
+            .collect();
+        // the arena is only freed after the reads into it are done
+        unsafe { ring.submitter().register_buffers(&io_vecs)? };
+        ring.submitter().register_files(files)?;
+        Ok(Self { arena, slot_size })
+    }
+
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    pub fn slot(&self, index: usize) -> &[u8] {
+        &self.arena[index * self.slot_size..][..self.slot_size]
+    }
+
+    pub fn slot_mut(&mut self, index: usize) -> &mut [u8] {
+        &mut self.arena[index * self.slot_size..][..self.slot_size]
+    }
+
+    /// Replace the file in slot `index`, the old one is closed once no read uses it.
+    pub fn set_file(&self, ring: &IoUring, index: usize, fd: RawFd) -> Result<()> {
+        ring.submitter()
+            .register_files_update(index as u32, &[fd])?;
+        Ok(())
+    }
+}
+
+/// A file opened by the reader or an index into the files registered with the ring.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Target {
+    Fd(types::Fd),
+    Fixed(types::Fixed),
+}
+
+impl From<types::Fd> for Target {
+    fn from(fd: types::Fd) -> Self {
+        Self::Fd(fd)
+    }
+}
+
+impl From<types::Fixed> for Target {
+    fn from(fixed: types::Fixed) -> Self {
+        Self::Fixed(fixed)
+    }
+}
+
+/// A negative cqe result is `-errno`, return the number of bytes read.
+pub(crate) fn check_cqe(result: i32, path: Option<&Path>, offset: u64) -> Result<usize> {
+    if result < 0 {
+        let context = ReadContext::new(path.map(Path::to_owned), offset);
+        return Err(Error::from_raw_os_io_error(-result).with_context(context));
+    }
+    Ok(result as usize)
+}
+
+/// A record that ends before `what` is complete.
+pub(crate) fn truncated(path: Option<&Path>, what: &str, offset: u64) -> Error {
+    let context = ReadContext::new(path.map(Path::to_owned), offset);
+    Error::DataLoss(format!("truncated {what} in {context}"))
+}
+
+/// Same as [`check_length`](crate::record::check_length), but the error names the file.
+pub(crate) fn check_read_length(
+    length: u64,
+    max_length: u64,
+    path: Option<&Path>,
+    offset: u64,
+) -> Result<()> {
+    if length > max_length {
+        let context = ReadContext::new(path.map(Path::to_owned), offset);
+        return Err(Error::DataLoss(format!(
+            "record length {length} in {context} exceeds the limit {max_length}"
+        )));
+    }
+    Ok(())
+}
+
+/// Verify the masked crc stored in `crc_buf`, `offset` is where the checked bytes start.
+pub(crate) fn verify_crc(
+    buf: &[u8],
+    crc_buf: &[u8],
+    path: Option<&Path>,
+    offset: u64,
+) -> Result<()> {
+    verify_masked_crc(buf, read_u32(crc_buf))
+        .map_err(|err| err.with_context(ReadContext::new(path.map(Path::to_owned), offset)))
+}
+
+/// `submit_and_wait` retried on `EINTR`.
+pub(crate) fn submit_and_wait(ring: &mut IoUring, want: usize) -> Result<()> {
+    loop {
+        match ring.submit_and_wait(want) {
+            Ok(_) => return Ok(()),
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Progress of a `readv` that may complete with fewer bytes than asked, which happens
+/// on network filesystems. The unfilled part of the iovecs is resubmitted, so only a
+/// read of 0 bytes means the end of the file.
+#[derive(Debug, Default)]
+pub(crate) struct ReadvState {
+    filled: usize,
+    // iovecs of the read in flight, they must stay alive until it completes
+    remaining: Vec<IoVec>,
+}
+
+impl ReadvState {
+    /// Read the unfilled part of `io_vecs`, which start at `offset` in the file.
+    pub fn build_readv_entry(
+        &mut self,
+        fd: impl Into<Target>,
+        io_vecs: &[IoVec],
+        offset: u64,
+        user_data: u64,
+    ) -> squeue::Entry {
+        self.remaining.clear();
+        let mut skip = self.filled;
+        for io_vec in io_vecs {
+            if skip >= io_vec.iov_len {
+                skip -= io_vec.iov_len;
+                continue;
+            }
+            self.remaining.push(IoVec {
+                iov_base: unsafe { io_vec.iov_base.cast::<u8>().add(skip).cast() },
+                iov_len: io_vec.iov_len - skip,
+            });
+            skip = 0;
+        }
+        let iovec = self.remaining.as_ptr() as *const _;
+        let len = self.remaining.len() as _;
+        let entry = match fd.into() {
+            Target::Fd(fd) => opcode::Readv::new(fd, iovec, len),
+            Target::Fixed(fixed) => opcode::Readv::new(fixed, iovec, len),
+        };
+        entry
+            .offset(offset + self.filled as u64)
+            .build()
+            .user_data(user_data)
+    }
+
+    /// Read the unfilled part of `buf`, which is in registered buffer `buf_index`.
+    pub fn build_read_fixed_entry(
+        &mut self,
+        file: types::Fixed,
+        buf: &mut [u8],
+        buf_index: u16,
+        offset: u64,
+        user_data: u64,
+    ) -> squeue::Entry {
+        let rest = &mut buf[self.filled..];
+        opcode::ReadFixed::new(file, rest.as_mut_ptr(), rest.len() as _, buf_index)
+            .offset(offset + self.filled as u64)
+            .build()
+            .user_data(user_data)
+    }
+
+    /// Count `bytes_read`, return true if the read is done because all `total` bytes are
+    /// filled or the file ended.
+    pub fn advance(&mut self, bytes_read: usize, total: usize) -> bool {
+        self.filled += bytes_read;
+        bytes_read == 0 || self.filled >= total
+    }
+
+    /// Bytes filled by a done read, the state is reset for the next one.
+    pub fn finish(&mut self) -> usize {
+        std::mem::take(&mut self.filled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn readv_resubmits_rest() {
+        let mut buf = [0u8; 12];
+        let (length, crc) = buf.split_at_mut(8);
+        let io_vecs = [IoVec::new(length), IoVec::new(crc)];
+
+        let mut state = ReadvState::default();
+        assert!(!state.advance(5, buf.len()));
+        state.build_readv_entry(types::Fd(0), &io_vecs, 100, 0);
+        let remaining: Vec<(usize, usize)> = state
+            .remaining
+            .iter()
+            .map(|io_vec| {
+                (
+                    io_vec.iov_base as usize - buf.as_ptr() as usize,
+                    io_vec.iov_len,
+                )
+            })
+            .collect();
+        assert_eq!(remaining, vec![(5, 3), (8, 4)]);
+
+        assert!(!state.advance(6, 12));
+        state.build_readv_entry(types::Fd(0), &io_vecs, 100, 0);
+        assert_eq!(state.remaining.len(), 1);
+        assert_eq!(state.remaining[0].iov_len, 1);
+        assert!(state.advance(1, 12));
+        assert_eq!(state.finish(), 12);
+
+        // end of file before all is filled
+        assert!(!state.advance(4, 12));
+        assert!(state.advance(0, 12));
+        assert_eq!(state.finish(), 4);
+    }
+}
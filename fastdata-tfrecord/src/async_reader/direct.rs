@@ -0,0 +1,241 @@
+use std::{
+    alloc::{self, Layout},
+    fmt,
+    fs::File,
+    ops::{Deref, DerefMut},
+    os::{fd::AsRawFd, unix::fs::OpenOptionsExt},
+    ptr::NonNull,
+};
+
+use io_uring::{squeue, types};
+
+use crate::{async_reader::ReadvState, error::Result, utils::IoVec};
+
+/// Alignment of buffers, offsets and lengths of `O_DIRECT` reads, a multiple of the
+/// logical block size of common devices.
+pub(crate) const DIRECT_ALIGNMENT: usize = 4096;
+
+/// Least bytes read at once by sequential readers, small records are then copied out
+/// of blocks already read.
+pub(crate) const DIRECT_READ_AHEAD: usize = 256 * 1024;
+
+fn align_down(offset: u64) -> u64 {
+    offset & !(DIRECT_ALIGNMENT as u64 - 1)
+}
+
+fn align_up(offset: u64) -> u64 {
+    align_down(offset + DIRECT_ALIGNMENT as u64 - 1)
+}
+
+/// Set `O_DIRECT` on `file`, reads then bypass the page cache.
+pub(crate) fn set_direct(file: &File) -> Result<()> {
+    let fd = file.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT) } < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Open `file` again with `O_DIRECT`, the flags of `file` are unchanged.
+pub(crate) fn reopen_direct(file: &File) -> Result<File> {
+    let path = format!("/proc/self/fd/{}", file.as_raw_fd());
+    let file = File::options()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(path)?;
+    Ok(file)
+}
+
+/// Zeroed bytes aligned to [`DIRECT_ALIGNMENT`].
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+unsafe impl Send for AlignedBuffer {}
+unsafe impl Sync for AlignedBuffer {}
+
+impl AlignedBuffer {
+    /// `len` is rounded up to a whole block.
+    fn new(len: usize) -> Self {
+        let len = align_up(len.max(1) as u64) as usize;
+        let layout = Self::layout(len);
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len }
+    }
+
+    fn layout(len: usize) -> Layout {
+        Layout::from_size_align(len, DIRECT_ALIGNMENT).expect("buffer too large")
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout(self.len)) }
+    }
+}
+
+/// Blocks of a file opened with `O_DIRECT`, records are copied out of them.
+///
+/// Blocks after a record are kept for the next one, so a file read sequentially
+/// has each block read once.
+pub(crate) struct DirectWindow {
+    buf: AlignedBuffer,
+    /// File offset of `buf[0]`, aligned
+    start: u64,
+    /// Bytes of `buf` read from the file
+    filled: usize,
+    /// End of the read in flight in `buf`
+    read_end: usize,
+    eof: bool,
+    state: ReadvState,
+}
+
+impl fmt::Debug for DirectWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirectWindow")
+            .field("start", &self.start)
+            .field("filled", &self.filled)
+            .field("eof", &self.eof)
+            .finish()
+    }
+}
+
+impl DirectWindow {
+    pub fn new() -> Self {
+        Self {
+            buf: AlignedBuffer::new(DIRECT_ALIGNMENT),
+            start: 0,
+            filled: 0,
+            read_end: 0,
+            eof: false,
+            state: ReadvState::default(),
+        }
+    }
+
+    /// Drop the blocks read, before reading another file.
+    pub fn clear(&mut self) {
+        self.filled = 0;
+        self.read_end = 0;
+        self.eof = false;
+    }
+
+    /// `len` bytes at `offset`, fewer if the file ends before, or `None` if they
+    /// must be read first.
+    pub fn get(&self, offset: u64, len: usize) -> Option<&[u8]> {
+        if offset < self.start {
+            return None;
+        }
+        let begin = (offset - self.start) as usize;
+        if begin + len <= self.filled {
+            Some(&self.buf[begin..begin + len])
+        } else if self.eof {
+            Some(&self.buf[begin.min(self.filled)..self.filled])
+        } else {
+            None
+        }
+    }
+
+    /// Prepare the read of the blocks missing for `len` bytes at `offset`, reading
+    /// at least `read_ahead` bytes from `offset`.
+    pub fn request(&mut self, offset: u64, len: usize, read_ahead: usize) {
+        let start = align_down(offset);
+        let read_end = (align_up(offset + len.max(read_ahead) as u64) - start) as usize;
+        let end = self.start + self.filled as u64;
+        let kept = if !self.eof && start >= self.start && start < end {
+            (end - start) as usize
+        } else {
+            0
+        };
+        if kept > 0 && kept < read_end {
+            // keep the blocks from `start` on
+            let shift = (start - self.start) as usize;
+            self.buf.copy_within(shift..self.filled, 0);
+            self.filled = kept;
+        } else {
+            // nothing to keep, or all of it, which is read again so a read is always done
+            self.filled = 0;
+        }
+        self.start = start;
+        self.eof = false;
+
+        if read_end > self.buf.len() {
+            let mut buf = AlignedBuffer::new(read_end);
+            buf[..self.filled].copy_from_slice(&self.buf[..self.filled]);
+            self.buf = buf;
+        }
+        self.read_end = read_end;
+    }
+
+    /// Read the requested blocks, or the rest of them after a short read.
+    pub fn build_read_entry(&mut self, fd: types::Fd, user_data: u64) -> squeue::Entry {
+        let offset = self.start + self.filled as u64;
+        let buf = &mut self.buf[self.filled..self.read_end];
+        self.state
+            .build_readv_entry(fd, &[IoVec::new(buf)], offset, user_data)
+    }
+
+    /// Count `bytes_read` of the read in flight, return true when it's done.
+    pub fn on_read(&mut self, bytes_read: usize) -> bool {
+        let total = self.read_end - self.filled;
+        if !self.state.advance(bytes_read, total) {
+            return false;
+        }
+        let filled = self.state.finish();
+        self.eof = filled < total;
+        self.filled += filled;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_keeps_blocks() {
+        let mut window = DirectWindow::new();
+        window.request(100, 10, 0);
+        assert_eq!((window.start, window.read_end), (0, DIRECT_ALIGNMENT));
+        assert!(window.get(100, 10).is_none());
+        window.buf[100] = 42;
+        assert!(window.on_read(DIRECT_ALIGNMENT));
+        assert_eq!(window.get(100, 10).unwrap()[0], 42);
+
+        // the block of offset 4000 is kept, the next one is read
+        window.request(4000, 200, 0);
+        assert_eq!((window.start, window.filled), (0, DIRECT_ALIGNMENT));
+        assert_eq!(window.read_end, 2 * DIRECT_ALIGNMENT);
+        assert!(!window.on_read(100));
+        assert!(window.on_read(0));
+        assert!(window.eof);
+        assert_eq!(window.get(4000, 200).unwrap().len(), 196);
+
+        // blocks already read are read again when nothing else is missing
+        window.request(0, 100, 0);
+        assert!(window.on_read(DIRECT_ALIGNMENT));
+        window.request(10, 100, 0);
+        assert_eq!((window.filled, window.read_end), (0, DIRECT_ALIGNMENT));
+
+        window.request(3 * DIRECT_ALIGNMENT as u64 + 1, 1, 2 * DIRECT_ALIGNMENT);
+        assert_eq!(window.start, 3 * DIRECT_ALIGNMENT as u64);
+        assert_eq!((window.filled, window.read_end), (0, 3 * DIRECT_ALIGNMENT));
+        assert_eq!(window.buf.as_ptr().align_offset(DIRECT_ALIGNMENT), 0);
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    async_reader::{check_cqe, check_read_length, submit_and_wait, truncated, ReadOptions},
+    constants::HEADER_SIZE,
+    error::{Error, ReadContext, Result},
+    record::{parse_header, record_size},
+};
+use io_uring::{opcode, types, IoUring};
+use slab::Slab;
+
+/// Index entries of one file, or why its scan failed.
+pub type ScanResult = Result<Vec<(u64, u64)>>;
+
+/// Scan state of one file, only one header read is in flight per file
+/// because the next offset depends on the length in the current header.
+struct Scan {
+    file_idx: usize,
+    /// Only for error messages
+    path: Option<PathBuf>,
+    size: u64,
+    offset: u64,
+    header: Box<[u8; HEADER_SIZE]>,
+    filled: usize,
+    entries: Vec<(u64, u64)>,
+}
+
+impl Scan {
+    fn build_read_entry(&mut self, file: &File, user_data: u64) -> io_uring::squeue::Entry {
+        let buf = &mut self.header[self.filled..];
+        opcode::Read::new(
+            types::Fd(file.as_raw_fd()),
+            buf.as_mut_ptr(),
+            buf.len() as _,
+        )
+        .offset(self.offset + self.filled as u64)
+        .build()
+        .user_data(user_data)
+    }
+
+    /// Handle `n` bytes read into the header, return true if the file is done.
+    fn advance(&mut self, n: usize, options: &ReadOptions) -> Result<bool> {
+        self.filled += n;
+        if self.filled < HEADER_SIZE {
+            if n == 0 {
+                return Err(truncated(self.path.as_deref(), "header", self.offset));
+            }
+            // a short read in the middle of a file, read the rest
+            return Ok(false);
+        }
+        self.filled = 0;
+
+        let path = self.path.as_deref();
+        let length = parse_header(&self.header[..], options.check_integrity)
+            .map_err(|err| err.with_context(ReadContext::new(self.path.clone(), self.offset)))?;
+        check_read_length(length, options.max_record_length, path, self.offset)?;
+        let end = self.offset + record_size(length);
+        if end > self.size {
+            return Err(truncated(path, "record", self.offset));
+        }
+        self.entries.push((self.offset, record_size(length)));
+        self.offset = end;
+        Ok(end == self.size)
+    }
+}
+
+/// Build the index of every file from record headers only, data is never read.
+///
+/// Up to `queue_depth` files are scanned at the same time, so header reads of
+/// different files are pipelined. The outer error is from the ring, a corrupted
+/// file only fails its own result.
+pub fn io_uring_scan_headers(files: &[File], options: ReadOptions) -> Result<Vec<ScanResult>> {
+    let paths = vec![None; files.len()];
+    scan_files(files, &paths, options)
+}
+
+/// Same as [`io_uring_scan_headers`], but errors name the file. A file that can't be
+/// opened fails only its own result.
+pub fn io_uring_scan_paths<P: AsRef<Path>>(
+    paths: &[P],
+    options: ReadOptions,
+) -> Result<Vec<ScanResult>> {
+    let mut files = Vec::with_capacity(paths.len());
+    let mut scanned_paths = Vec::with_capacity(paths.len());
+    let mut open_errors = Vec::new();
+    for (file_idx, path) in paths.iter().enumerate() {
+        let path = path.as_ref().to_owned();
+        match File::open(&path) {
+            Ok(file) => {
+                files.push(file);
+                scanned_paths.push(Some(path));
+            }
+            Err(err) => {
+                let context = ReadContext::new(Some(path), 0);
+                open_errors.push((file_idx, Error::from(err).with_context(context)));
+            }
+        }
+    }
+
+    let mut results = scan_files(&files, &scanned_paths, options)?.into_iter();
+    let mut open_errors = open_errors.into_iter().peekable();
+    Ok((0..paths.len())
+        .map(
+            |file_idx| match open_errors.next_if(|(idx, _)| *idx == file_idx) {
+                Some((_, err)) => Err(err),
+                None => results.next().expect("every opened file is scanned"),
+            },
+        )
+        .collect())
+}
+
+/// `paths` are only for error messages.
+fn scan_files(
+    files: &[File],
+    paths: &[Option<PathBuf>],
+    options: ReadOptions,
+) -> Result<Vec<ScanResult>> {
+    let mut ring = IoUring::new(options.queue_depth)?;
+    let mut results: Vec<Option<ScanResult>> = files.iter().map(|_| None).collect();
+    let mut scans: Slab<Scan> = Slab::with_capacity(options.queue_depth as usize);
+    let mut next_file = 0;
+    let mut num_reads = 0;
+
+    let result = (|| {
+        loop {
+            // keep the ring busy with new files
+            while scans.len() < options.queue_depth as usize && next_file < files.len() {
+                let file_idx = next_file;
+                next_file += 1;
+                let path = paths[file_idx].clone();
+                let size = match files[file_idx].metadata() {
+                    Ok(metadata) => metadata.len(),
+                    Err(err) => {
+                        let context = ReadContext::new(path, 0);
+                        results[file_idx] = Some(Err(Error::from(err).with_context(context)));
+                        continue;
+                    }
+                };
+                if size == 0 {
+                    results[file_idx] = Some(Ok(Vec::new()));
+                    continue;
+                }
+
+                let entry = scans.vacant_entry();
+                let user_data = entry.key() as u64;
+                let scan = entry.insert(Scan {
+                    file_idx,
+                    path,
+                    size,
+                    offset: 0,
+                    header: Box::new([0; HEADER_SIZE]),
+                    filled: 0,
+                    entries: Vec::new(),
+                });
+                let read_e = scan.build_read_entry(&files[file_idx], user_data);
+                unsafe {
+                    ring.submission().push(&read_e)?;
+                }
+                num_reads += 1;
+            }
+
+            if num_reads == 0 {
+                break;
+            }
+            submit_and_wait(&mut ring, 1)?;
+
+            let completed: Vec<(u64, i32)> = ring
+                .completion()
+                .map(|cqe| (cqe.user_data(), cqe.result()))
+                .collect();
+            num_reads -= completed.len();
+            for (user_data, res) in completed {
+                let scan_idx = user_data as usize;
+                let scan = &mut scans[scan_idx];
+
+                let done = if res == -libc::EINTR || res == -libc::EAGAIN {
+                    Ok(false)
+                } else {
+                    check_cqe(res, scan.path.as_deref(), scan.offset)
+                        .and_then(|n| scan.advance(n, &options))
+                };
+
+                match done {
+                    Ok(false) => {
+                        let read_e = scan.build_read_entry(&files[scan.file_idx], user_data);
+                        unsafe {
+                            ring.submission().push(&read_e)?;
+                        }
+                        num_reads += 1;
+                    }
+                    Ok(true) => {
+                        let scan = scans.remove(scan_idx);
+                        results[scan.file_idx] = Some(Ok(scan.entries));
+                    }
+                    Err(err) => {
+                        let scan = scans.remove(scan_idx);
+                        results[scan.file_idx] = Some(Err(err));
+                    }
+                }
+            }
+        }
+        Ok(())
+    })();
+    if let Err(err) = result {
+        // header reads in flight point into `scans`
+        drain(&mut ring, num_reads, scans);
+        return Err(err);
+    }
+
+    Ok(results
+        .into_iter()
+        .map(|result| result.expect("every file is scanned"))
+        .collect())
+}
+
+/// Wait for the reads in flight after an error, `scans` are leaked if the ring fails
+/// again because the kernel may still write into them.
+fn drain(ring: &mut IoUring, mut num_reads: usize, scans: Slab<Scan>) {
+    while num_reads > 0 {
+        if submit_and_wait(ring, 1).is_err() {
+            std::mem::forget(scans);
+            return;
+        }
+        num_reads -= ring.completion().count();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_reader::TfrecordReader;
+    use crate::sync_writer::TfrecordWriter;
+
+    #[test]
+    fn scan_matches_reader() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut paths = Vec::new();
+        for num_records in [5, 0, 3] {
+            let path = dir.path().join(format!("{num_records}.tfrecord"));
+            let mut writer = TfrecordWriter::create(&path).unwrap();
+            for i in 0..num_records {
+                writer.write(&vec![i as u8; 10 + i * 7]).unwrap();
+            }
+            writer.flush().unwrap();
+            drop(writer);
+            paths.push(path);
+        }
+        let truncated = std::fs::read(&paths[0]).unwrap();
+        let truncated_path = dir.path().join("truncated.tfrecord");
+        std::fs::write(&truncated_path, &truncated[..truncated.len() - 3]).unwrap();
+        paths.push(truncated_path);
+
+        let files: Vec<File> = paths.iter().map(|path| File::open(path).unwrap()).collect();
+        let results = io_uring_scan_headers(&files, ReadOptions::new(2, true)).unwrap();
+        assert!(matches!(results[3], Err(Error::DataLoss(_))));
+
+        paths.insert(1, dir.path().join("missing.tfrecord"));
+        let mut results = io_uring_scan_paths(&paths, ReadOptions::new(2, true)).unwrap();
+        assert!(matches!(results.remove(1), Err(Error::IoError(_))));
+        paths.remove(1);
+        for (path, result) in paths[..3].iter().zip(&results) {
+            let expected = TfrecordReader::open(path, true)
+                .unwrap()
+                .verified_indices()
+                .collect::<Result<Vec<_>>>()
+                .unwrap();
+            assert_eq!(result.as_ref().unwrap(), &expected);
+        }
+        match &results[3] {
+            Err(Error::DataLoss(message)) => assert!(message.contains("truncated.tfrecord")),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+}
@@ -1,186 +1,732 @@
+use std::collections::VecDeque;
+use std::fs::File;
 use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
 
-use crate::constants::U32_SIZE;
-use crate::error::Result;
+use crate::async_reader::{
+    check_cqe, check_read_length,
+    direct::{reopen_direct, DirectWindow, DIRECT_READ_AHEAD},
+    scatter, submit_and_wait, truncated, verify_crc, ReadOptions, ReadvState, Registered, Target,
+};
+use crate::buffer_pool::{BufferPool, PooledBuffer};
+use crate::constants::{HEADER_SIZE, U32_SIZE, U64_SIZE};
+use crate::error::{Error, ReadContext, Result};
+use crate::record::read_u64;
 use crate::utils::IoVec;
-use crate::{constants::U64_SIZE, crc32c::verify_masked_crc};
-use io_uring::{opcode, types, IoUring};
+use io_uring::{types, IoUring};
 use slab::Slab;
 
+/// Crc of data and the header of the next record.
+const TAIL_SIZE: usize = U32_SIZE + HEADER_SIZE;
+
+/// Records a lane reads ahead of its turn with [`ReadOptions::interleave`], its read
+/// waits while that many are queued.
+const INTERLEAVE_AHEAD: usize = 4;
+
 #[derive(Debug)]
 pub struct Buffer {
     pub fd: std::fs::File,
-    pub io_vecs: Vec<IoVec>,
     pub offset: u64,
+    /// Only for error messages, `None` if the reader was given files.
+    pub path: Option<PathBuf>,
+    /// `None` while reading the first header
+    data: Option<PooledBuffer>,
+    tail: Box<[u8; TAIL_SIZE]>,
+    state: ReadvState,
+    /// Blocks read with `O_DIRECT`
+    window: Option<DirectWindow>,
+    /// Lane of the file with [`ReadOptions::interleave`]
+    lane: usize,
 }
 
 impl Buffer {
+    pub fn new(fd: File, path: Option<PathBuf>) -> Self {
+        Self {
+            fd,
+            offset: 0,
+            path,
+            data: None,
+            tail: Box::new([0; TAIL_SIZE]),
+            state: ReadvState::default(),
+            window: None,
+            lane: 0,
+        }
+    }
+
+    /// Read the file in aligned blocks through a descriptor of our own opened with
+    /// `O_DIRECT`, setting it on the file given would also change its duplicates.
+    fn set_direct(&mut self) -> Result<()> {
+        self.fd = reopen_direct(&self.fd)
+            .map_err(|err| err.with_context(ReadContext::new(self.path.clone(), self.offset)))?;
+        let mut window = DirectWindow::new();
+        window.request(self.offset, self.total_length(), DIRECT_READ_AHEAD);
+        self.window = Some(window);
+        Ok(())
+    }
+
     pub fn get_raw_fd(&self) -> types::Fd {
         types::Fd(self.fd.as_raw_fd())
     }
 
-    pub fn get_readv(&self) -> opcode::Readv {
-        opcode::Readv::new(
-            self.get_raw_fd(),
-            self.io_vecs.as_ptr() as *const _,
-            self.io_vecs.len() as _,
-        )
-        .offset(self.offset)
+    pub fn is_read_header(&self) -> bool {
+        self.data.is_none()
     }
 
-    pub fn is_read_header(&self) -> bool {
-        self.io_vecs.len() == 2
+    /// Read the header, or data, crc and the next header, or the rest after a short read.
+    ///
+    /// With `registered` buffers, the file is in slot `user_data` and a read that fits
+    /// goes into the buffer of that slot.
+    pub(crate) fn build_readv_entry(
+        &mut self,
+        user_data: u64,
+        registered: Option<&mut Registered>,
+    ) -> io_uring::squeue::Entry {
+        let raw_fd = self.get_raw_fd();
+        if let Some(window) = self.window.as_mut() {
+            return window.build_read_entry(raw_fd, user_data);
+        }
+        let fd: Target = match registered {
+            Some(registered) => {
+                let slot = user_data as usize;
+                let file = types::Fixed(slot as u32);
+                let total_length = self.total_length();
+                if total_length <= registered.slot_size() {
+                    let buf = &mut registered.slot_mut(slot)[..total_length];
+                    return self.state.build_read_fixed_entry(
+                        file,
+                        buf,
+                        slot as u16,
+                        self.offset,
+                        user_data,
+                    );
+                }
+                file.into()
+            }
+            None => raw_fd.into(),
+        };
+        let (crc, header) = self.tail.split_at_mut(U32_SIZE);
+        match self.data.as_mut() {
+            None => self
+                .state
+                .build_readv_entry(fd, &[IoVec::new(header)], self.offset, user_data),
+            Some(data) => self.state.build_readv_entry(
+                fd,
+                &[IoVec::new(data), IoVec::new(crc), IoVec::new(header)],
+                self.offset,
+                user_data,
+            ),
+        }
     }
 
-    pub fn build_readv_entry(&self, user_data: u64) -> io_uring::squeue::Entry {
-        self.get_readv().build().user_data(user_data)
+    fn total_length(&self) -> usize {
+        match &self.data {
+            None => HEADER_SIZE,
+            Some(data) => data.len() + TAIL_SIZE,
+        }
+    }
+
+    /// Copy a read that went elsewhere to the data and tail.
+    fn copy_from(&mut self, bytes: &[u8]) {
+        match self.data.as_mut() {
+            None => scatter(bytes, &mut [&mut self.tail[U32_SIZE..]]),
+            Some(data) => scatter(bytes, &mut [data, &mut self.tail[..]]),
+        }
+    }
+
+    /// Handle `bytes_read` of a read in flight, return false at the end of the file.
+    fn on_read<F>(
+        &mut self,
+        bytes_read: usize,
+        options: &ReadOptions,
+        pool: &BufferPool,
+        slot: Option<&[u8]>,
+        cb: &mut F,
+    ) -> Result<bool>
+    where
+        F: FnMut(PooledBuffer),
+    {
+        if let Some(mut window) = self.window.take() {
+            let more = self.read_window(&mut window, bytes_read, options, pool, cb);
+            self.window = Some(window);
+            return more;
+        }
+        let total_length = self.total_length();
+        if !self.state.advance(bytes_read, total_length) {
+            // a short read, read the rest
+            return Ok(true);
+        }
+        if let Some(slot) = slot.filter(|slot| total_length <= slot.len()) {
+            self.copy_from(&slot[..total_length]);
+        }
+        let filled = self.state.finish();
+        self.advance(filled, options, pool, cb)
+    }
+
+    /// Handle the records in the blocks read with `O_DIRECT` until more blocks are
+    /// needed, return false at the end of the file.
+    fn read_window<F>(
+        &mut self,
+        window: &mut DirectWindow,
+        bytes_read: usize,
+        options: &ReadOptions,
+        pool: &BufferPool,
+        cb: &mut F,
+    ) -> Result<bool>
+    where
+        F: FnMut(PooledBuffer),
+    {
+        if !window.on_read(bytes_read) {
+            // a short read, read the rest
+            return Ok(true);
+        }
+        loop {
+            let total_length = self.total_length();
+            match window.get(self.offset, total_length) {
+                Some(bytes) => {
+                    self.copy_from(bytes);
+                    if !self.advance(bytes.len(), options, pool, cb)? {
+                        return Ok(false);
+                    }
+                }
+                None => {
+                    window.request(self.offset, total_length, DIRECT_READ_AHEAD);
+                    return Ok(true);
+                }
+            }
+        }
+    }
+
+    /// Handle a done read of `filled` bytes, return false at the end of the file.
+    fn advance<F>(
+        &mut self,
+        filled: usize,
+        options: &ReadOptions,
+        pool: &BufferPool,
+        cb: &mut F,
+    ) -> Result<bool>
+    where
+        F: FnMut(PooledBuffer),
+    {
+        let path = self.path.as_deref();
+        let header_offset = match self.data.take() {
+            None => {
+                if filled == 0 {
+                    return Ok(false);
+                }
+                if filled < HEADER_SIZE {
+                    return Err(truncated(path, "header", self.offset));
+                }
+                self.offset
+            }
+            Some(data) => {
+                let record_offset = self.offset - HEADER_SIZE as u64;
+                let record_end = data.len() + U32_SIZE;
+                if filled < record_end {
+                    return Err(truncated(path, "record", record_offset));
+                }
+
+                if options.check_integrity {
+                    verify_crc(&data, &self.tail[..U32_SIZE], path, record_offset)?;
+                }
+                let next_offset = self.offset + record_end as u64;
+
+                // Pass the buffer out
+                cb(data);
+
+                if filled == record_end {
+                    // the last record
+                    return Ok(false);
+                }
+                if filled < record_end + HEADER_SIZE {
+                    return Err(truncated(path, "header", next_offset));
+                }
+                next_offset
+            }
+        };
+
+        let header = &self.tail[U32_SIZE..];
+        let length = read_u64(header);
+        if options.check_integrity {
+            verify_crc(
+                &header[..U64_SIZE],
+                &header[U64_SIZE..],
+                path,
+                header_offset,
+            )?;
+        }
+        check_read_length(length, options.max_record_length, path, header_offset)?;
+
+        self.data = Some(pool.get(length as usize));
+        self.offset = header_offset + HEADER_SIZE as u64;
+        Ok(true)
     }
 }
 
 /// This function work without index
-pub fn io_uring_loop<T, F>(
-    mut source: T,
-    queue_depth: u32,
-    check_integrity: bool,
-    cb: F,
-) -> Result<()>
+pub fn io_uring_loop<T, F>(source: T, queue_depth: u32, check_integrity: bool, cb: F) -> Result<()>
 where
     T: Iterator<Item = std::fs::File>,
-    F: Fn(Vec<u8>),
+    F: Fn(PooledBuffer),
 {
-    let mut ring = IoUring::new(queue_depth)?;
+    io_uring_loop_with_options(source, ReadOptions::new(queue_depth, check_integrity), cb)
+}
 
-    let max_reads = queue_depth as usize;
-    let mut buffers = Slab::with_capacity(max_reads);
-    let mut pending = Vec::with_capacity(max_reads);
+/// An I/O error or corrupted record stops the loop, reads in flight are waited for
+/// and the error is returned.
+pub fn io_uring_loop_with_options<T, F>(source: T, options: ReadOptions, cb: F) -> Result<()>
+where
+    T: Iterator<Item = std::fs::File>,
+    F: Fn(PooledBuffer),
+{
+    read_files(source.map(|fd| Ok(Buffer::new(fd, None))), options, cb)
+}
 
-    for _ in 0..max_reads {
-        if let Some(fd) = source.next() {
-            let buffer = Buffer {
-                fd,
-                io_vecs: vec![
-                    IoVec::from(vec![0; U64_SIZE]), // length
-                    IoVec::from(vec![0; U32_SIZE]), // crc_of_length
-                ],
-                offset: 0,
+/// Same as [`io_uring_loop_with_options`], but errors name the file.
+pub fn io_uring_loop_with_paths<T, P, F>(paths: T, options: ReadOptions, cb: F) -> Result<()>
+where
+    T: IntoIterator<Item = P>,
+    P: AsRef<Path>,
+    F: Fn(PooledBuffer),
+{
+    let source = paths.into_iter().map(|path| {
+        let path = path.as_ref().to_owned();
+        match File::open(&path) {
+            Ok(fd) => Ok(Buffer::new(fd, Some(path))),
+            Err(err) => Err(Error::from(err).with_context(ReadContext::new(Some(path), 0))),
+        }
+    });
+    read_files(source, options, cb)
+}
+
+/// Records queued in lanes with [`ReadOptions::interleave`], file `n` of the source
+/// goes to lane `n % lanes` and the lanes pass one record each in turn.
+struct Interleave {
+    /// Files taken from the source before their lane is free
+    files: Vec<VecDeque<Buffer>>,
+    num_files: usize,
+    source_done: bool,
+    records: Vec<VecDeque<PooledBuffer>>,
+    /// The lane read its last file
+    finished: Vec<bool>,
+    turn: usize,
+}
+
+impl Interleave {
+    fn new(lanes: usize) -> Self {
+        Self {
+            files: (0..lanes).map(|_| VecDeque::new()).collect(),
+            num_files: 0,
+            source_done: false,
+            records: (0..lanes).map(|_| VecDeque::new()).collect(),
+            finished: vec![false; lanes],
+            turn: 0,
+        }
+    }
+
+    /// The next file of `lane`, files of other lanes are kept until their lane is free.
+    fn next_file<T>(&mut self, source: &mut T, lane: usize) -> Option<Result<Buffer>>
+    where
+        T: Iterator<Item = Result<Buffer>>,
+    {
+        if let Some(buffer) = self.files[lane].pop_front() {
+            return Some(Ok(buffer));
+        }
+        while !self.source_done {
+            let Some(buffer) = source.next() else {
+                self.source_done = true;
+                break;
             };
-            let buf_idx = buffers.insert(buffer);
-            let buf_ref = &mut buffers[buf_idx];
-            let read_e = buf_ref.build_readv_entry(buf_idx as _);
-            pending.push(read_e);
-        } else {
-            break;
+            let file_lane = self.num_files % self.files.len();
+            self.num_files += 1;
+            match buffer {
+                Ok(buffer) if file_lane != lane => self.files[file_lane].push_back(buffer),
+                buffer => return Some(buffer),
+            }
         }
+        None
     }
 
-    let mut num_reads = 0;
+    fn is_full(&self, lane: usize) -> bool {
+        self.records[lane].len() >= INTERLEAVE_AHEAD
+    }
+
+    /// Pass records in turn until the lane whose turn it is has none queued.
+    fn emit<F: Fn(PooledBuffer)>(&mut self, cb: &F) {
+        let lanes = self.records.len();
+        let mut skipped = 0;
+        while skipped < lanes {
+            let lane = self.turn;
+            match self.records[lane].pop_front() {
+                Some(record) => {
+                    cb(record);
+                    skipped = 0;
+                }
+                None if self.finished[lane] => skipped += 1,
+                None => break,
+            }
+            self.turn = (lane + 1) % lanes;
+        }
+    }
+}
+
+/// The next file from `source`, read in `lane`.
+fn next_file<T>(
+    source: &mut T,
+    interleave: Option<&mut Interleave>,
+    lane: usize,
+) -> Option<Result<Buffer>>
+where
+    T: Iterator<Item = Result<Buffer>>,
+{
+    let buffer = match interleave {
+        Some(interleave) => interleave.next_file(source, lane),
+        None => source.next(),
+    };
+    buffer.map(|buffer| {
+        buffer.map(|mut buffer| {
+            buffer.lane = lane;
+            buffer
+        })
+    })
+}
+
+fn read_files<T, F>(mut source: T, options: ReadOptions, cb: F) -> Result<()>
+where
+    T: Iterator<Item = Result<Buffer>>,
+    F: Fn(PooledBuffer),
+{
+    options.validate()?;
+    let mut ring = IoUring::new(options.queue_depth)?;
 
-    for read_e in pending.drain(..) {
-        unsafe {
-            ring.submission().push(&read_e)?;
+    let max_reads = options.queue_depth as usize;
+    let pool = BufferPool::new(max_reads * 2);
+    let mut registered = options
+        .fixed_buffer_size
+        .map(|size| Registered::new(&ring, max_reads, size, &vec![-1; max_reads]))
+        .transpose()?;
+    let mut interleave = options.interleave.then(|| Interleave::new(max_reads));
+    let mut buffers = Slab::with_capacity(max_reads);
+    let mut pending = Vec::with_capacity(max_reads);
+    // buffers of lanes with full queues, read once records are passed
+    let mut parked: Vec<usize> = Vec::new();
+    let mut error = None;
+
+    for lane in 0..max_reads {
+        match next_file(&mut source, interleave.as_mut(), lane) {
+            Some(buffer) => {
+                let read_e = buffer.and_then(|buffer| {
+                    start_read(&ring, &mut buffers, registered.as_mut(), &options, buffer)
+                });
+                match read_e {
+                    Ok(read_e) => pending.push(read_e),
+                    Err(err) => {
+                        error = Some(err);
+                        break;
+                    }
+                }
+            }
+            None => match interleave.as_mut() {
+                Some(interleave) => interleave.finished[lane] = true,
+                None => break,
+            },
         }
-        num_reads += 1;
     }
 
+    let mut num_reads = 0;
+
     loop {
-        ring.submit_and_wait(1)?;
-        for cqe in ring.completion() {
-            // TODO: return Err
-            assert!(cqe.result() >= 0);
-
-            let buf_idx = cqe.user_data() as usize;
-            let bytes_read = cqe.result();
-            if bytes_read == 0 {
-                let _buffer = buffers.remove(buf_idx);
-
-                if let Some(fd) = source.next() {
-                    let buffer = Buffer {
-                        fd,
-                        io_vecs: vec![
-                            IoVec::from(vec![0; U64_SIZE]),
-                            IoVec::from(vec![0; U32_SIZE]),
-                        ],
-                        offset: 0,
-                    };
-                    let buf_idx = buffers.insert(buffer);
-                    let buf_ref = &buffers[buf_idx];
-                    let read_e = buf_ref.build_readv_entry(buf_idx as _);
-                    pending.push(read_e);
-                }
-            } else {
-                // dbg!(bytes_read);
-                let buf_ref = &mut buffers[buf_idx];
-                if buf_ref.is_read_header() {
-                    let length_buf = buf_ref.io_vecs[0]
-                        .as_slice()
-                        .try_into()
-                        .expect("fail to convert to array");
-                    let length = u64::from_le_bytes(length_buf);
-
-                    if check_integrity {
-                        let masked_crc_buf = buf_ref.io_vecs[1]
-                            .as_slice()
-                            .try_into()
-                            .expect("fail to convert to array");
-                        let masked_crc = u32::from_le_bytes(masked_crc_buf);
-                        assert!(verify_masked_crc(&length_buf, masked_crc).is_ok());
-                    }
+        // Stop reading after an error, but wait for the reads in flight
+        if error.is_none() {
+            for read_e in pending.drain(..) {
+                unsafe {
+                    ring.submission().push(&read_e)?;
+                }
+                num_reads += 1;
+            }
+        }
+
+        if num_reads == 0 {
+            break;
+        }
+
+        if let Err(err) = submit_and_wait(&mut ring, 1) {
+            // the kernel may still write into them
+            std::mem::forget(buffers);
+            std::mem::forget(registered);
+            return Err(err);
+        }
 
-                    buf_ref.io_vecs = vec![
-                        IoVec::from(vec![0; length as usize]), // data
-                        IoVec::from(vec![0; U32_SIZE]),        // crc of data
-                        IoVec::from(vec![0; U64_SIZE]),        // length
-                        IoVec::from(vec![0; U32_SIZE]),        // crc of length
-                    ];
-                    buf_ref.offset += (U64_SIZE + U32_SIZE) as u64;
-
-                    let read_e = buf_ref.build_readv_entry(buf_idx as _);
-                    pending.push(read_e);
-                } else {
-                    let data_buf = Vec::from(buf_ref.io_vecs[0]);
-                    if check_integrity {
-                        let masked_crc_buf = buf_ref.io_vecs[1].as_slice().try_into().unwrap();
-                        let masked_crc = u32::from_le_bytes(masked_crc_buf);
-                        assert!(verify_masked_crc(&data_buf, masked_crc).is_ok());
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completed {
+            num_reads -= 1;
+            let buf_idx = user_data as usize;
+            if error.is_some() {
+                buffers.remove(buf_idx);
+                continue;
+            }
+
+            let buf_ref = &mut buffers[buf_idx];
+            let lane = buf_ref.lane;
+            let slot = registered
+                .as_ref()
+                .map(|registered| registered.slot(buf_idx));
+            let more =
+                check_cqe(result, buf_ref.path.as_deref(), buf_ref.offset).and_then(|bytes_read| {
+                    match interleave.as_mut() {
+                        Some(interleave) => {
+                            let records = &mut interleave.records[lane];
+                            buf_ref.on_read(bytes_read, &options, &pool, slot, &mut |record| {
+                                records.push_back(record)
+                            })
+                        }
+                        None => buf_ref
+                            .on_read(bytes_read, &options, &pool, slot, &mut |record| cb(record)),
                     }
+                });
+            match more {
+                Ok(true) if interleave.as_ref().is_some_and(|i| i.is_full(lane)) => {
+                    parked.push(buf_idx)
+                }
+                Ok(true) => {
+                    pending.push(buf_ref.build_readv_entry(buf_idx as _, registered.as_mut()))
+                }
+                Ok(false) => {
+                    let _buffer = buffers.remove(buf_idx);
+                    match next_file(&mut source, interleave.as_mut(), lane) {
+                        Some(buffer) => {
+                            let read_e = buffer.and_then(|buffer| {
+                                start_read(
+                                    &ring,
+                                    &mut buffers,
+                                    registered.as_mut(),
+                                    &options,
+                                    buffer,
+                                )
+                            });
+                            match read_e {
+                                Ok(read_e) => pending.push(read_e),
+                                Err(err) => error = Some(err),
+                            }
+                        }
+                        None => {
+                            if let Some(interleave) = interleave.as_mut() {
+                                interleave.finished[lane] = true;
+                            }
+                        }
+                    }
+                }
+                Err(err) => {
+                    buffers.remove(buf_idx);
+                    error = Some(err);
+                }
+            }
+        }
 
-                    let data_length = data_buf.len();
+        if let (Some(interleave), None) = (interleave.as_mut(), &error) {
+            interleave.emit(&cb);
+            parked.retain(|&buf_idx| {
+                let buf_ref = &mut buffers[buf_idx];
+                if interleave.is_full(buf_ref.lane) {
+                    return true;
+                }
+                pending.push(buf_ref.build_readv_entry(buf_idx as _, registered.as_mut()));
+                false
+            });
+        }
+    }
 
-                    // Pass the buffer out
-                    cb(data_buf);
+    match error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
 
-                    let length_buf = buf_ref.io_vecs[2].as_slice().try_into().unwrap();
-                    let length = u64::from_le_bytes(length_buf);
+/// Put `buffer` in a free slot and build its first read, with `registered` buffers
+/// its file is registered in the same slot.
+fn start_read(
+    ring: &IoUring,
+    buffers: &mut Slab<Buffer>,
+    registered: Option<&mut Registered>,
+    options: &ReadOptions,
+    mut buffer: Buffer,
+) -> Result<io_uring::squeue::Entry> {
+    if options.direct_io {
+        buffer.set_direct()?;
+    }
+    let buf_idx = buffers.vacant_key();
+    if let Some(registered) = registered.as_deref() {
+        registered.set_file(ring, buf_idx, buffer.fd.as_raw_fd())?;
+    }
+    buffers.insert(buffer);
+    Ok(buffers[buf_idx].build_readv_entry(buf_idx as _, registered))
+}
 
-                    if check_integrity {
-                        let masked_crc_buf = buf_ref.io_vecs[3].as_slice().try_into().unwrap();
-                        let masked_crc = u32::from_le_bytes(masked_crc_buf);
-                        assert!(verify_masked_crc(&length_buf, masked_crc).is_ok());
-                    }
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
 
-                    // Reset data buffer
-                    buf_ref.io_vecs[0] = IoVec::from(vec![0; length as usize]);
-                    buf_ref.offset += (data_length + U32_SIZE + U64_SIZE + U32_SIZE) as u64;
+    use super::*;
+    use crate::constants::HEADER_SIZE;
+    use crate::sync_writer::TfrecordWriter;
 
-                    let read_e = buf_ref.build_readv_entry(buf_idx as _);
-                    pending.push(read_e);
-                }
+    #[test]
+    fn fixed_buffers() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths: Vec<_> = (0..6)
+            .map(|i| dir.path().join(format!("{i}.tfrecord")))
+            .collect();
+        for (i, path) in paths.iter().enumerate() {
+            let mut writer = TfrecordWriter::create(path).unwrap();
+            for j in 0..10 {
+                writer.write(&vec![i as u8; 10 * j]).unwrap();
             }
-            num_reads -= 1;
+            writer.flush().unwrap();
+        }
+
+        let read = |fixed_buffer_size| {
+            let records = Mutex::new(Vec::new());
+            let mut options = ReadOptions::new(4, true);
+            options.fixed_buffer_size = fixed_buffer_size;
+            io_uring_loop_with_paths(&paths, options, |record| {
+                records.lock().unwrap().push(record.into_vec())
+            })
+            .unwrap();
+            let mut records = records.into_inner().unwrap();
+            records.sort();
+            records
+        };
+        let expected = read(None);
+        assert_eq!(expected.len(), 60);
+        // records larger than the buffers are read with readv
+        assert_eq!(read(Some(64)), expected);
+        assert_eq!(read(Some(4096)), expected);
+    }
+
+    #[test]
+    fn direct_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let mut writer = TfrecordWriter::create(&path).unwrap();
+        // small records share blocks, large ones span the read ahead
+        let records: Vec<Vec<u8>> = (0..200)
+            .map(|i| vec![i as u8; if i % 50 == 7 { 300_000 } else { i * 13 }])
+            .collect();
+        for record in &records {
+            writer.write(record).unwrap();
         }
+        writer.flush().unwrap();
+        drop(writer);
 
-        // Will submit at the beginning
-        for read_e in pending.drain(..) {
-            unsafe {
-                ring.submission().push(&read_e)?;
+        let mut options = ReadOptions::new(4, true);
+        options.direct_io = true;
+        let decoded = Mutex::new(Vec::new());
+        io_uring_loop_with_paths([&path], options, |record| {
+            decoded.lock().unwrap().push(record.into_vec())
+        })
+        .unwrap();
+        assert_eq!(decoded.into_inner().unwrap(), records);
+
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &buf[..buf.len() - 3]).unwrap();
+        let result = io_uring_loop_with_paths([&path], options, |_| {});
+        assert!(matches!(result, Err(Error::DataLoss(_))));
+
+        options.fixed_buffer_size = Some(4096);
+        assert!(io_uring_loop_with_paths([&path], options, |_| {}).is_err());
+
+        // the file of the caller is left without O_DIRECT
+        std::fs::write(&path, &buf).unwrap();
+        let file = File::open(&path).unwrap();
+        let clone = file.try_clone().unwrap();
+        options.fixed_buffer_size = None;
+        io_uring_loop_with_options([file].into_iter(), options, |_| {}).unwrap();
+        let flags = unsafe { libc::fcntl(clone.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_DIRECT, 0);
+    }
+
+    #[test]
+    fn interleave() {
+        let dir = tempfile::tempdir().unwrap();
+        let lengths = [30, 5, 12, 0, 20];
+        let paths: Vec<_> = (0..lengths.len())
+            .map(|i| dir.path().join(format!("{i}.tfrecord")))
+            .collect();
+        for (i, path) in paths.iter().enumerate() {
+            let mut writer = TfrecordWriter::create(path).unwrap();
+            for j in 0..lengths[i] {
+                writer.write(&[i as u8, j as u8]).unwrap();
             }
-            num_reads += 1;
+            writer.flush().unwrap();
         }
 
-        if num_reads == 0 {
-            break;
+        // files 0, 2 and 4 are read in lane 0, files 1 and 3 in lane 1
+        let mut lanes: Vec<VecDeque<Vec<u8>>> = vec![VecDeque::new(); 2];
+        for (i, &length) in lengths.iter().enumerate() {
+            lanes[i % 2].extend((0..length).map(|j| vec![i as u8, j as u8]));
+        }
+        let mut expected = Vec::new();
+        while lanes.iter().any(|lane| !lane.is_empty()) {
+            expected.extend(lanes.iter_mut().filter_map(VecDeque::pop_front));
+        }
+
+        for fixed_buffer_size in [None, Some(64)] {
+            let records = Mutex::new(Vec::new());
+            let mut options = ReadOptions::new(2, true);
+            options.interleave = true;
+            options.fixed_buffer_size = fixed_buffer_size;
+            io_uring_loop_with_paths(&paths, options, |record| {
+                records.lock().unwrap().push(record.into_vec())
+            })
+            .unwrap();
+            assert_eq!(records.into_inner().unwrap(), expected);
         }
     }
 
-    Ok(())
+    #[test]
+    fn corrupted_record_is_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let mut writer = TfrecordWriter::create(&path).unwrap();
+        for i in 0..4 {
+            writer.write(&[i; 20]).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let records = Mutex::new(Vec::new());
+        let options = ReadOptions::new(4, true);
+        io_uring_loop_with_paths([&path], options, |record| {
+            records.lock().unwrap().push(record)
+        })
+        .unwrap();
+        assert_eq!(records.lock().unwrap().len(), 4);
+
+        let mut buf = std::fs::read(&path).unwrap();
+        let second_record = HEADER_SIZE + 20 + U32_SIZE;
+        buf[second_record + HEADER_SIZE] ^= 0xff;
+        std::fs::write(&path, buf).unwrap();
+
+        match io_uring_loop_with_paths([&path], options, |_| {}) {
+            Err(Error::ChecksumMismatch {
+                context: Some(context),
+                ..
+            }) => {
+                assert_eq!(context.path.as_deref(), Some(path.as_path()));
+                assert_eq!(context.offset, second_record as u64);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &buf[..buf.len() - 3]).unwrap();
+        let mut options = ReadOptions::new(4, false);
+        options.fixed_buffer_size = Some(64);
+        let result = io_uring_loop_with_paths([&path], options, |_| {});
+        assert!(matches!(result, Err(Error::DataLoss(_))));
+        let result = io_uring_loop_with_paths([&path], ReadOptions::new(4, false), |_| {});
+        assert!(matches!(result, Err(Error::DataLoss(_))));
+    }
 }
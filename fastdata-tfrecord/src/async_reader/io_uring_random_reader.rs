@@ -1,18 +1,25 @@
 use std::{fs::File, os::fd::AsRawFd, path::Path};
 
 use crate::{
-    constants::{U32_SIZE, U64_SIZE},
-    crc32c::verify_masked_crc,
-    error::Result,
-    indexing::sync_reader::IndexReader,
+    async_reader::{
+        check_cqe, check_read_length,
+        direct::{reopen_direct, DirectWindow},
+        scatter, submit_and_wait, verify_crc, ReadOptions, ReadvState, Registered, Target,
+    },
+    buffer_pool::{BufferPool, PooledBuffer},
+    constants::{HEADER_SIZE, U32_SIZE, U64_SIZE},
+    error::{Error, ReadContext, Result},
+    indexing::{
+        cache::{open_or_build_index, IndexCache},
+        source::IndexSource,
+    },
+    record::{parse_header, record_size},
     utils::IoVec,
 };
-use io_uring::{opcode, types, IoUring};
+use io_uring::{types, IoUring};
 use memmap2::Mmap;
 use slab::Slab;
 
-use crate::indexing::sync_reader::MmapIndexReader;
-
 pub struct AsyncRandomReader {
     file: File,
     index: Mmap,
@@ -33,28 +40,173 @@ pub struct AsyncRandomReader {
 
 #[derive(Debug)]
 pub struct Buffer {
-    pub io_vecs: Vec<IoVec>,
+    pub offset: u64,
+    /// Header, then crc of data
+    frame: Box<[u8; HEADER_SIZE + U32_SIZE]>,
+    data: PooledBuffer,
+    state: ReadvState,
+    /// Blocks read with `O_DIRECT`
+    window: Option<DirectWindow>,
+    /// Slot of the file in the files registered with the ring
+    pub(crate) fixed_file: u32,
 }
 
 impl Buffer {
-    pub fn build_readv_entry(
-        &self,
+    pub(crate) fn new(offset: u64, data: PooledBuffer, direct_io: bool) -> Self {
+        let mut buffer = Self {
+            offset,
+            frame: Box::new([0; HEADER_SIZE + U32_SIZE]),
+            data: PooledBuffer::from(Vec::new()),
+            state: ReadvState::default(),
+            window: direct_io.then(DirectWindow::new),
+            fixed_file: 0,
+        };
+        buffer.set_record(offset, data);
+        buffer
+    }
+
+    /// Read the record at `offset` into `data` next.
+    pub(crate) fn set_record(&mut self, offset: u64, data: PooledBuffer) {
+        self.offset = offset;
+        self.data = data;
+        let total_length = self.total_length();
+        if let Some(window) = self.window.as_mut() {
+            // with `O_DIRECT`, read the blocks of the record
+            window.request(offset, total_length, 0);
+        }
+    }
+
+    /// Drop the blocks read with `O_DIRECT`, before reading records of another file.
+    pub(crate) fn clear_blocks(&mut self) {
+        if let Some(window) = self.window.as_mut() {
+            window.clear();
+        }
+    }
+
+    /// Hand the data of a read record out.
+    pub(crate) fn take_data(&mut self) -> PooledBuffer {
+        std::mem::replace(&mut self.data, PooledBuffer::from(Vec::new()))
+    }
+
+    /// Read the record at `offset`, or the rest of it after a short read.
+    ///
+    /// With `registered` buffers, the file is the fixed file `fixed_file` and a record
+    /// that fits is read into the buffer of slot `user_data`.
+    pub(crate) fn build_readv_entry(
+        &mut self,
         file: &File,
         offset: u64,
         user_data: u64,
+        registered: Option<&mut Registered>,
     ) -> io_uring::squeue::Entry {
-        opcode::Readv::new(
-            types::Fd(file.as_raw_fd()),
-            self.io_vecs.as_ptr() as *const _,
-            self.io_vecs.len() as _,
+        if let Some(window) = self.window.as_mut() {
+            return window.build_read_entry(types::Fd(file.as_raw_fd()), user_data);
+        }
+        let fd: Target = match registered {
+            Some(registered) => {
+                let slot = user_data as usize;
+                let total_length = self.total_length();
+                if total_length <= registered.slot_size() {
+                    let buf = &mut registered.slot_mut(slot)[..total_length];
+                    return self.state.build_read_fixed_entry(
+                        types::Fixed(self.fixed_file),
+                        buf,
+                        slot as u16,
+                        offset,
+                        user_data,
+                    );
+                }
+                types::Fixed(self.fixed_file).into()
+            }
+            None => types::Fd(file.as_raw_fd()).into(),
+        };
+        let (header, crc) = self.frame.split_at_mut(HEADER_SIZE);
+        self.state.build_readv_entry(
+            fd,
+            &[
+                IoVec::new(header),
+                IoVec::new(&mut self.data),
+                IoVec::new(crc),
+            ],
+            offset,
+            user_data,
         )
-        .offset(offset)
-        .build()
-        .user_data(user_data)
     }
 
     pub fn total_length(&self) -> usize {
-        self.io_vecs.iter().map(|v| v.iov_len).sum()
+        self.data.len() + HEADER_SIZE + U32_SIZE
+    }
+
+    /// Handle `bytes_read` of the read in flight, return false after a short read,
+    /// then the rest must be read. A done read is checked with [`Buffer::verify`].
+    pub(crate) fn on_read_checked(
+        &mut self,
+        bytes_read: usize,
+        slot: Option<&[u8]>,
+        path: Option<&Path>,
+        check_integrity: bool,
+    ) -> Result<bool> {
+        let Some(filled) = self.on_read(bytes_read, slot) else {
+            return Ok(false);
+        };
+        if filled < self.total_length() {
+            let context = ReadContext::new(path.map(Path::to_owned), self.offset);
+            return Err(Error::DataLoss(format!(
+                "truncated record in {context}, the index expects {} bytes",
+                self.total_length()
+            )));
+        }
+        self.verify(path, check_integrity)?;
+        Ok(true)
+    }
+
+    /// Count `bytes_read` of the read in flight, return the bytes of the record read
+    /// once it's done. A read into a registered buffer or blocks is copied out.
+    fn on_read(&mut self, bytes_read: usize, slot: Option<&[u8]>) -> Option<usize> {
+        let total_length = self.total_length();
+        if let Some(mut window) = self.window.take() {
+            let filled = window.on_read(bytes_read).then(|| {
+                let bytes = window
+                    .get(self.offset, total_length)
+                    .expect("blocks of the record are read");
+                self.copy_from(bytes);
+                bytes.len()
+            });
+            self.window = Some(window);
+            return filled;
+        }
+        if !self.state.advance(bytes_read, total_length) {
+            return None;
+        }
+        if let Some(slot) = slot.filter(|slot| total_length <= slot.len()) {
+            self.copy_from(&slot[..total_length]);
+        }
+        Some(self.state.finish())
+    }
+
+    /// Copy a record read elsewhere to the frame and data.
+    fn copy_from(&mut self, bytes: &[u8]) {
+        let (header, crc) = self.frame.split_at_mut(HEADER_SIZE);
+        scatter(bytes, &mut [header, &mut self.data, crc]);
+    }
+
+    /// The header must agree with the index, crcs are checked with `check_integrity`.
+    fn verify(&self, path: Option<&Path>, check_integrity: bool) -> Result<()> {
+        let context = || ReadContext::new(path.map(Path::to_owned), self.offset);
+        let (header, crc) = self.frame.split_at(HEADER_SIZE);
+        let length =
+            parse_header(header, check_integrity).map_err(|err| err.with_context(context()))?;
+        if length != self.data.len() as u64 {
+            return Err(Error::DataLoss(format!(
+                "record length {length} in {} doesn't match the index ({})",
+                context(),
+                self.total_length()
+            )));
+        }
+        if check_integrity {
+            verify_crc(&self.data, crc, path, self.offset)?;
+        }
+        Ok(())
     }
 }
 
@@ -67,103 +219,281 @@ pub fn io_uring_loop<P, F>(
 ) -> Result<()>
 where
     P: AsRef<Path>,
-    F: Fn(Vec<u8>),
+    F: Fn(PooledBuffer),
+{
+    io_uring_loop_with_options(
+        path,
+        index_path,
+        ReadOptions::new(queue_depth, check_integrity),
+        cb,
+    )
+}
+
+/// A missing or stale index is built in memory, see [`io_uring_loop_with_cache`].
+pub fn io_uring_loop_with_options<P, F>(
+    path: P,
+    index_path: Option<P>,
+    options: ReadOptions,
+    cb: F,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+    F: Fn(PooledBuffer),
+{
+    io_uring_loop_with_cache(path, index_path, &IndexCache::Memory, options, cb)
+}
+
+/// Build a missing or stale index and keep it according to `cache`, see [`open_or_build_index`].
+pub fn io_uring_loop_with_cache<P, Q, F>(
+    path: P,
+    index_path: Option<Q>,
+    cache: &IndexCache,
+    options: ReadOptions,
+    cb: F,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: Fn(PooledBuffer),
 {
-    let index_path = index_path
-        .map(|p| p.as_ref().to_owned())
-        .unwrap_or_else(|| path.as_ref().with_extension("tfrecord.idx"));
-    let file = File::open(path)?;
-    let index_reader = IndexReader::open(&index_path)?;
+    let index_reader = open_or_build_index(
+        path.as_ref(),
+        index_path.as_ref().map(|p| p.as_ref()),
+        cache,
+    )?;
+    let file = File::open(&path)?;
+    read_with_index(&file, Some(path.as_ref()), &index_reader, options, cb)
+}
+
+/// Read every record of `index` from `file`, in index order.
+///
+/// An I/O error or corrupted record stops the loop, reads in flight are waited for
+/// and the error is returned.
+pub fn io_uring_loop_with_index<I, F>(
+    file: &File,
+    index: &I,
+    options: ReadOptions,
+    cb: F,
+) -> Result<()>
+where
+    I: IndexSource,
+    F: Fn(PooledBuffer),
+{
+    read_with_index(file, None, index, options, cb)
+}
+
+fn read_with_index<I, F>(
+    file: &File,
+    path: Option<&Path>,
+    index: &I,
+    options: ReadOptions,
+    cb: F,
+) -> Result<()>
+where
+    I: IndexSource,
+    F: Fn(PooledBuffer),
+{
+    let ReadOptions {
+        queue_depth,
+        check_integrity,
+        max_record_length,
+        fixed_buffer_size,
+        direct_io,
+        interleave: _,
+    } = options;
+    options.validate()?;
+    // a descriptor of our own, O_DIRECT would break buffered I/O of the caller
+    let direct_file;
+    let file = if direct_io {
+        direct_file = reopen_direct(file)
+            .map_err(|err| err.with_context(ReadContext::new(path.map(Path::to_owned), 0)))?;
+        &direct_file
+    } else {
+        file
+    };
     let mut ring = IoUring::new(queue_depth)?;
 
-    let mut index_iter = index_reader.into_iter();
+    let mut index_iter = index.iter();
 
     let mut num_reads = 0;
     let max_reads = queue_depth as usize;
 
     let mut pending = Vec::new();
-    let mut buffers: Slab<_> = Slab::with_capacity(max_reads);
+    let mut buffers: Slab<Buffer> = Slab::with_capacity(max_reads);
+    let pool = BufferPool::new(max_reads * 2);
+    let mut registered = fixed_buffer_size
+        .map(|size| Registered::new(&ring, max_reads, size, &[file.as_raw_fd()]))
+        .transpose()?;
+    let mut error = None;
 
     for _ in 0..max_reads {
         if let Some((offset, length)) = index_iter.next() {
-            let buffer = Buffer {
-                io_vecs: vec![
-                    IoVec::from(vec![0; U64_SIZE]), // length
-                    IoVec::from(vec![0; U32_SIZE]), // crc_of_length
-                    IoVec::from(vec![0; length as usize - U32_SIZE * 2 - U64_SIZE]), // data
-                    IoVec::from(vec![0; U32_SIZE]), // crc_of_data
-                ],
-            };
-            let buf_idx = buffers.insert(buffer);
-            let buf_ref = &mut buffers[buf_idx];
-            let read_e = buf_ref.build_readv_entry(&file, offset, buf_idx as _);
+            check_index_length(length, max_record_length, path, offset)?;
+            let data = pool.get(data_length(length));
+            let buf_idx = buffers.insert(Buffer::new(offset, data, direct_io));
+            let buf_ref: &mut Buffer = &mut buffers[buf_idx];
+            let read_e = buf_ref.build_readv_entry(file, offset, buf_idx as _, registered.as_mut());
             pending.push(read_e);
         } else {
             break;
         }
     }
 
-    for read_e in pending.drain(..) {
-        unsafe {
-            ring.submission().push(&read_e)?;
-        }
-        num_reads += 1;
-    }
     loop {
-        ring.submit_and_wait(1)?;
+        // Stop reading after an error, but wait for the reads in flight
+        if error.is_none() {
+            for read_e in pending.drain(..) {
+                unsafe {
+                    ring.submission().push(&read_e)?;
+                }
+                num_reads += 1;
+            }
+        }
 
-        for cqe in ring.completion() {
-            assert!(cqe.result() >= 0);
-            let buf_idx = cqe.user_data() as usize;
-            let _bytes_read = cqe.result() as usize;
+        if num_reads == 0 {
+            break;
+        }
 
-            let buf_ref = &mut buffers[buf_idx];
+        if let Err(err) = submit_and_wait(&mut ring, 1) {
+            // the kernel may still write into them
+            std::mem::forget(buffers);
+            std::mem::forget(registered);
+            return Err(err);
+        }
 
-            // assert!(bytes_read == buf_ref.total_length());
-
-            if check_integrity {
-                let masked_crc_of_length_buf = buf_ref.io_vecs[1]
-                    .as_slice()
-                    .try_into()
-                    .expect("fail to convert to array");
-                let masked_crc_of_length = u32::from_le_bytes(masked_crc_of_length_buf);
-                let length_buf = buf_ref.io_vecs[0].as_slice();
-                assert!(verify_masked_crc(length_buf, masked_crc_of_length).is_ok());
-
-                let masked_crc_of_data_buf = buf_ref.io_vecs[3]
-                    .as_slice()
-                    .try_into()
-                    .expect("fail to convert to array");
-                let masked_crc_of_data = u32::from_le_bytes(masked_crc_of_data_buf);
-                let data_buf = buf_ref.io_vecs[2].as_slice();
-                assert!(verify_masked_crc(data_buf, masked_crc_of_data).is_ok());
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completed {
+            num_reads -= 1;
+            let buf_idx = user_data as usize;
+            if error.is_some() {
+                buffers.remove(buf_idx);
+                continue;
             }
 
-            // TODO: unsafe, please use other way
-            let data_buf = Vec::from(buf_ref.io_vecs[2]);
-            cb(data_buf);
-
-            if let Some((offset, length)) = index_iter.next() {
-                buf_ref.io_vecs[2] =
-                    IoVec::from(vec![0; length as usize - U32_SIZE * 2 - U64_SIZE]);
-                let read_e = buf_ref.build_readv_entry(&file, offset, buf_idx as _);
-                pending.push(read_e);
+            let buf_ref = &mut buffers[buf_idx];
+            let slot = registered
+                .as_ref()
+                .map(|registered| registered.slot(buf_idx));
+            let checked = check_cqe(result, path, buf_ref.offset).and_then(|bytes_read| {
+                buf_ref.on_read_checked(bytes_read, slot, path, check_integrity)
+            });
+            match checked {
+                Ok(true) => {}
+                Ok(false) => {
+                    // a short read, read the rest
+                    let offset = buf_ref.offset;
+                    let read_e =
+                        buf_ref.build_readv_entry(file, offset, buf_idx as _, registered.as_mut());
+                    pending.push(read_e);
+                    continue;
+                }
+                Err(err) => {
+                    buffers.remove(buf_idx);
+                    error = Some(err);
+                    continue;
+                }
             }
 
-            num_reads -= 1;
-        }
+            cb(buf_ref.take_data());
 
-        for read_e in pending.drain(..) {
-            unsafe {
-                ring.submission().push(&read_e)?;
+            match index_iter.next() {
+                Some((offset, length)) => {
+                    if let Err(err) = check_index_length(length, max_record_length, path, offset) {
+                        buffers.remove(buf_idx);
+                        error = Some(err);
+                        continue;
+                    }
+                    buf_ref.set_record(offset, pool.get(data_length(length)));
+                    let read_e =
+                        buf_ref.build_readv_entry(file, offset, buf_idx as _, registered.as_mut());
+                    pending.push(read_e);
+                }
+                None => {
+                    buffers.remove(buf_idx);
+                }
             }
-            num_reads += 1;
         }
+    }
 
-        if num_reads == 0 {
-            break;
-        }
+    match error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Size of the data in a whole record of an index entry.
+pub(crate) fn data_length(length: u64) -> usize {
+    length as usize - U32_SIZE * 2 - U64_SIZE
+}
+
+/// The index stores the size of the whole record, which must fit a header and a crc.
+pub(crate) fn check_index_length(
+    length: u64,
+    max_record_length: u64,
+    path: Option<&Path>,
+    offset: u64,
+) -> Result<()> {
+    if length < record_size(0) {
+        let context = ReadContext::new(path.map(Path::to_owned), offset);
+        return Err(Error::DataLoss(format!(
+            "record length {length} in {context} is smaller than a header"
+        )));
     }
+    check_read_length(length - record_size(0), max_record_length, path, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{sync_reader::TfrecordReader, sync_writer::TfrecordWriter};
 
-    Ok(())
+    #[test]
+    fn fixed_buffers_and_direct_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let mut writer = TfrecordWriter::create(&path).unwrap();
+        let records: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i; 7 * i as usize]).collect();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        for (fixed_buffer_size, direct_io) in [
+            (None, false),
+            (Some(64), false),
+            (Some(4096), false),
+            (None, true),
+        ] {
+            let decoded = Mutex::new(Vec::new());
+            let mut options = ReadOptions::new(4, true);
+            options.fixed_buffer_size = fixed_buffer_size;
+            options.direct_io = direct_io;
+            io_uring_loop_with_options(&path, None, options, |record| {
+                decoded.lock().unwrap().push(record.into_vec())
+            })
+            .unwrap();
+            let mut decoded = decoded.into_inner().unwrap();
+            decoded.sort_by_key(|record| record.len());
+            assert_eq!(decoded, records);
+        }
+
+        // the file of the caller is left without O_DIRECT
+        let file = File::open(&path).unwrap();
+        let index: Vec<(u64, u64)> = TfrecordReader::open(&path, true)
+            .unwrap()
+            .indices()
+            .map(|index| index.unwrap())
+            .collect();
+        let mut options = ReadOptions::new(4, true);
+        options.direct_io = true;
+        io_uring_loop_with_index(&file, &index, options, |_| {}).unwrap();
+        let flags = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_DIRECT, 0);
+    }
 }
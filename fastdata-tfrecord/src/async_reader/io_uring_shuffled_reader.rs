@@ -0,0 +1,481 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use io_uring::IoUring;
+use slab::Slab;
+
+use crate::{
+    async_reader::{
+        check_cqe,
+        direct::set_direct,
+        io_uring_random_reader::{check_index_length, data_length, Buffer},
+        submit_and_wait, ReadOptions, Registered,
+    },
+    buffer_pool::{BufferPool, PooledBuffer},
+    error::{Error, ReadContext, Result},
+    indexing::{global_index::GlobalIndex, source::IndexSource},
+};
+
+/// Order of the records passed to the callback of [`io_uring_loop_shuffled`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordOrder {
+    /// As soon as they are read.
+    Completion,
+    /// As requested, records read early wait for the ones before them.
+    Requested,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ShuffledReadOptions {
+    pub read: ReadOptions,
+    /// Shards open at once, the least recently used one is closed to open another.
+    pub max_open_files: usize,
+    pub order: RecordOrder,
+}
+
+impl ShuffledReadOptions {
+    pub fn new(read: ReadOptions) -> Self {
+        Self {
+            read,
+            max_open_files: 256,
+            order: RecordOrder::Completion,
+        }
+    }
+}
+
+struct OpenFile {
+    shard: usize,
+    file: File,
+    /// Reads in flight, the file isn't closed before they are done
+    reads: usize,
+    last_used: u64,
+}
+
+/// Shards open for reading, in slots that are also their fixed file slots when
+/// files are registered with the ring.
+struct OpenFiles {
+    files: Vec<OpenFile>,
+    slots: HashMap<usize, usize>,
+    max_open_files: usize,
+    clock: u64,
+}
+
+impl OpenFiles {
+    fn new(max_open_files: usize) -> Self {
+        Self {
+            files: Vec::new(),
+            slots: HashMap::new(),
+            max_open_files: max_open_files.max(1),
+            clock: 0,
+        }
+    }
+
+    /// Slot of `shard`, which is opened if needed, or `None` if every open file has
+    /// reads in flight.
+    fn open(
+        &mut self,
+        shard: usize,
+        path: &Path,
+        options: &ReadOptions,
+        ring: &IoUring,
+        registered: Option<&Registered>,
+    ) -> Result<Option<usize>> {
+        self.clock += 1;
+        if let Some(&slot) = self.slots.get(&shard) {
+            self.files[slot].last_used = self.clock;
+            return Ok(Some(slot));
+        }
+
+        let slot = if self.files.len() < self.max_open_files {
+            self.files.len()
+        } else {
+            let lru = self
+                .files
+                .iter()
+                .enumerate()
+                .filter(|(_, file)| file.reads == 0)
+                .min_by_key(|(_, file)| file.last_used)
+                .map(|(slot, _)| slot);
+            match lru {
+                Some(slot) => slot,
+                None => return Ok(None),
+            }
+        };
+
+        let context = || ReadContext::new(Some(path.to_owned()), 0);
+        let file = File::open(path).map_err(|err| Error::from(err).with_context(context()))?;
+        if options.direct_io {
+            set_direct(&file).map_err(|err| err.with_context(context()))?;
+        }
+        if let Some(registered) = registered {
+            registered.set_file(ring, slot, file.as_raw_fd())?;
+        }
+        let open_file = OpenFile {
+            shard,
+            file,
+            reads: 0,
+            last_used: self.clock,
+        };
+        if slot < self.files.len() {
+            let closed = std::mem::replace(&mut self.files[slot], open_file);
+            self.slots.remove(&closed.shard);
+        } else {
+            self.files.push(open_file);
+        }
+        self.slots.insert(shard, slot);
+        Ok(Some(slot))
+    }
+}
+
+/// A read in flight of request `seq`.
+struct Read {
+    buffer: Buffer,
+    seq: usize,
+    request: (usize, usize),
+    slot: usize,
+}
+
+/// Read the `(shard, record)` pairs of `requests` from `shards`, e.g. a permutation of
+/// every record of a dataset for an exact shuffle.
+///
+/// Every shard is a path and its index. `queue_depth` reads stay in flight across the
+/// shards, and at most `max_open_files` of them are open at once. The callback gets
+/// the pair and the data in `order`, with [`RecordOrder::Requested`] at most twice
+/// `queue_depth` records are kept until the ones before them are read.
+///
+/// An unknown shard or record, an I/O error or a corrupted record stops the loop,
+/// reads in flight are waited for and the error is returned.
+pub fn io_uring_loop_shuffled<P, I, T, F>(
+    shards: &[(P, I)],
+    requests: T,
+    options: ShuffledReadOptions,
+    cb: F,
+) -> Result<()>
+where
+    P: AsRef<Path>,
+    I: IndexSource,
+    T: IntoIterator<Item = (usize, usize)>,
+    F: Fn((usize, usize), PooledBuffer),
+{
+    let ShuffledReadOptions {
+        read: read_options,
+        max_open_files,
+        order,
+    } = options;
+    read_options.validate()?;
+    let mut ring = IoUring::new(read_options.queue_depth)?;
+
+    let max_reads = read_options.queue_depth as usize;
+    let max_ahead = max_reads * 2;
+    let pool = BufferPool::new(max_reads * 2);
+    let mut files = OpenFiles::new(max_open_files);
+    let mut registered = read_options
+        .fixed_buffer_size
+        .map(|size| Registered::new(&ring, max_reads, size, &vec![-1; files.max_open_files]))
+        .transpose()?;
+    let mut reads: Slab<Read> = Slab::with_capacity(max_reads);
+    // with the shard they last read
+    let mut spare_buffers: Vec<(usize, Buffer)> = Vec::new();
+    let mut requests = requests.into_iter().enumerate().peekable();
+    let mut reordered = BTreeMap::new();
+    let mut next_seq = 0;
+    let mut pending = Vec::with_capacity(max_reads);
+    let mut num_reads = 0;
+    let mut error = None;
+
+    loop {
+        // Start reads until the queue is full, or every open file is busy
+        while error.is_none() && reads.len() < max_reads {
+            let Some(&(seq, request)) = requests.peek() else {
+                break;
+            };
+            if order == RecordOrder::Requested && seq >= next_seq + max_ahead {
+                break;
+            }
+
+            let (shard, record) = request;
+            let started = lookup(shards, shard, record).and_then(|(path, offset, length)| {
+                check_index_length(length, read_options.max_record_length, Some(path), offset)?;
+                let Some(slot) =
+                    files.open(shard, path, &read_options, &ring, registered.as_ref())?
+                else {
+                    return Ok(None);
+                };
+
+                let data = pool.get(data_length(length));
+                let mut buffer = match spare_buffers.pop() {
+                    Some((last_shard, mut buffer)) => {
+                        if last_shard != shard {
+                            buffer.clear_blocks();
+                        }
+                        buffer.set_record(offset, data);
+                        buffer
+                    }
+                    None => Buffer::new(offset, data, read_options.direct_io),
+                };
+                buffer.fixed_file = slot as u32;
+                files.files[slot].reads += 1;
+                let read_idx = reads.insert(Read {
+                    buffer,
+                    seq,
+                    request,
+                    slot,
+                });
+                let file = &files.files[slot].file;
+                let read_e = reads[read_idx].buffer.build_readv_entry(
+                    file,
+                    offset,
+                    read_idx as _,
+                    registered.as_mut(),
+                );
+                Ok(Some(read_e))
+            });
+            match started {
+                Ok(Some(read_e)) => {
+                    requests.next();
+                    pending.push(read_e);
+                }
+                Ok(None) => break,
+                Err(err) => error = Some(err),
+            }
+        }
+
+        // Stop reading after an error, but wait for the reads in flight
+        if error.is_none() {
+            for read_e in pending.drain(..) {
+                unsafe {
+                    ring.submission().push(&read_e)?;
+                }
+                num_reads += 1;
+            }
+        }
+
+        if num_reads == 0 {
+            break;
+        }
+
+        if let Err(err) = submit_and_wait(&mut ring, 1) {
+            // the kernel may still write into them
+            std::mem::forget(reads);
+            std::mem::forget(registered);
+            return Err(err);
+        }
+
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completed {
+            num_reads -= 1;
+            let read_idx = user_data as usize;
+            let read = &mut reads[read_idx];
+            let path = shards[read.request.0].0.as_ref();
+            let checked = match error {
+                Some(_) => Ok(true),
+                None => check_cqe(result, Some(path), read.buffer.offset).and_then(|bytes_read| {
+                    let slot = registered
+                        .as_ref()
+                        .map(|registered| registered.slot(read_idx));
+                    read.buffer.on_read_checked(
+                        bytes_read,
+                        slot,
+                        Some(path),
+                        read_options.check_integrity,
+                    )
+                }),
+            };
+            match checked {
+                Ok(true) => {}
+                Ok(false) => {
+                    // a short read, read the rest
+                    let file = &files.files[read.slot].file;
+                    let offset = read.buffer.offset;
+                    let read_e = read.buffer.build_readv_entry(
+                        file,
+                        offset,
+                        read_idx as _,
+                        registered.as_mut(),
+                    );
+                    pending.push(read_e);
+                    continue;
+                }
+                Err(err) => error = Some(err),
+            }
+
+            let mut read = reads.remove(read_idx);
+            files.files[read.slot].reads -= 1;
+            if error.is_none() {
+                let data = read.buffer.take_data();
+                match order {
+                    RecordOrder::Completion => cb(read.request, data),
+                    RecordOrder::Requested => {
+                        reordered.insert(read.seq, (read.request, data));
+                        while let Some((request, data)) = reordered.remove(&next_seq) {
+                            cb(request, data);
+                            next_seq += 1;
+                        }
+                    }
+                }
+            }
+            spare_buffers.push((read.request.0, read.buffer));
+        }
+    }
+
+    match error {
+        Some(err) => Err(err),
+        None => Ok(()),
+    }
+}
+
+/// Same as [`io_uring_loop_shuffled`], with the shards and indexes of a dataset manifest.
+/// Records are numbered from 0 in every shard.
+pub fn io_uring_loop_with_global_index<T, F>(
+    index: &GlobalIndex,
+    requests: T,
+    options: ShuffledReadOptions,
+    cb: F,
+) -> Result<()>
+where
+    T: IntoIterator<Item = (usize, usize)>,
+    F: Fn((usize, usize), PooledBuffer),
+{
+    let shards: Vec<(PathBuf, _)> = index
+        .shard_paths()
+        .iter()
+        .enumerate()
+        .map(|(shard, path)| (path.clone(), index.shard_index(shard).unwrap()))
+        .collect();
+    io_uring_loop_shuffled(&shards, requests, options, cb)
+}
+
+/// Path, offset and length of a record.
+fn lookup<P, I>(shards: &[(P, I)], shard: usize, record: usize) -> Result<(&Path, u64, u64)>
+where
+    P: AsRef<Path>,
+    I: IndexSource,
+{
+    let (path, index) = shards.get(shard).ok_or(Error::IndexOutOfRange {
+        index: shard,
+        len: shards.len(),
+    })?;
+    let (offset, length) = index.get(record).ok_or(Error::IndexOutOfRange {
+        index: record,
+        len: index.len(),
+    })?;
+    Ok((path.as_ref(), offset, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::{
+        indexing::cache::{open_or_build_index, IndexCache},
+        sync_writer::TfrecordWriter,
+    };
+
+    #[test]
+    fn shuffled_across_shards() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut shards = Vec::new();
+        for shard in 0..5u8 {
+            let path = dir.path().join(format!("{shard}.tfrecord"));
+            let mut writer = TfrecordWriter::create(&path).unwrap();
+            for record in 0..20u8 {
+                writer
+                    .write(&vec![shard * 20 + record; record as usize + 1])
+                    .unwrap();
+            }
+            writer.flush().unwrap();
+            drop(writer);
+            let index = open_or_build_index(&path, None::<&Path>, &IndexCache::Memory).unwrap();
+            shards.push((path, index));
+        }
+
+        // a fixed permutation of every record
+        let requests: Vec<(usize, usize)> = (0..100)
+            .map(|i| (i * 37) % 100)
+            .map(|id| (id / 20, id % 20))
+            .collect();
+        let expected =
+            |(shard, record): (usize, usize)| vec![(shard * 20 + record) as u8; record + 1];
+
+        for (order, fixed_buffer_size, direct_io) in [
+            (RecordOrder::Requested, None, false),
+            (RecordOrder::Completion, Some(64), false),
+            (RecordOrder::Requested, None, true),
+        ] {
+            let mut read_options = ReadOptions::new(8, true);
+            read_options.fixed_buffer_size = fixed_buffer_size;
+            read_options.direct_io = direct_io;
+            let mut options = ShuffledReadOptions::new(read_options);
+            options.max_open_files = 2;
+            options.order = order;
+
+            let decoded = Mutex::new(Vec::new());
+            io_uring_loop_shuffled(
+                &shards,
+                requests.iter().copied(),
+                options,
+                |request, data| {
+                    assert_eq!(&*data, expected(request));
+                    decoded.lock().unwrap().push(request);
+                },
+            )
+            .unwrap();
+            let mut decoded = decoded.into_inner().unwrap();
+            if order == RecordOrder::Completion {
+                decoded.sort_by_key(|request| requests.iter().position(|r| r == request));
+            }
+            assert_eq!(decoded, requests);
+        }
+
+        let options = ShuffledReadOptions::new(ReadOptions::new(8, true));
+        let result = io_uring_loop_shuffled(&shards, [(0, 1), (0, 20)], options, |_, _| {});
+        assert!(matches!(
+            result,
+            Err(Error::IndexOutOfRange { index: 20, len: 20 })
+        ));
+    }
+
+    #[test]
+    fn direct_io_blocks_stay_with_their_shard() {
+        // shards of several blocks, so records aren't read at the end of the file
+        let dir = tempfile::tempdir().unwrap();
+        let mut shards = Vec::new();
+        for shard in 0..2u8 {
+            let path = dir.path().join(format!("{shard}.tfrecord"));
+            let mut writer = TfrecordWriter::create(&path).unwrap();
+            for record in 0..200u8 {
+                writer.write(&[shard, record].repeat(20)).unwrap();
+            }
+            writer.flush().unwrap();
+            drop(writer);
+            let index = open_or_build_index(&path, None::<&Path>, &IndexCache::Memory).unwrap();
+            shards.push((path, index));
+        }
+
+        let requests: Vec<(usize, usize)> = (0..200).flat_map(|i| [(0, i), (1, i)]).collect();
+        for check_integrity in [false, true] {
+            let mut read_options = ReadOptions::new(1, check_integrity);
+            read_options.direct_io = true;
+            let decoded = Mutex::new(Vec::new());
+            io_uring_loop_shuffled(
+                &shards,
+                requests.iter().copied(),
+                ShuffledReadOptions::new(read_options),
+                |(shard, record), data| {
+                    assert_eq!(&*data, [shard as u8, record as u8].repeat(20));
+                    decoded.lock().unwrap().push((shard, record));
+                },
+            )
+            .unwrap();
+            assert_eq!(decoded.into_inner().unwrap(), requests);
+        }
+    }
+}
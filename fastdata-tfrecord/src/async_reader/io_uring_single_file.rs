@@ -1,38 +1,85 @@
+use crate::async_reader::{
+    check_cqe, check_read_length,
+    direct::{reopen_direct, DirectWindow, DIRECT_READ_AHEAD},
+    scatter, submit_and_wait, truncated, verify_crc, ReadvState,
+};
+use crate::buffer_pool::{BufferPool, PooledBuffer};
+use crate::constants::{DEFAULT_MAX_RECORD_LENGTH, HEADER_SIZE, U32_SIZE, U64_SIZE};
+use crate::error::{Error, Result};
+use crate::record::read_u64;
 use crate::utils::IoVec;
-use crate::{crc32c::verify_masked_crc, error::Result};
-use io_uring::{opcode, types, IoUring};
+use io_uring::{types, IoUring};
 use slab::Slab;
 use std::cmp::Reverse;
-use std::io::Read;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
 use std::{collections::BinaryHeap, fs::File, os::fd::AsRawFd};
 
-const U64_SIZE: usize = std::mem::size_of::<u64>();
-const U32_SIZE: usize = std::mem::size_of::<u32>();
+/// Crc of data and the header of the next record.
+const TAIL_SIZE: usize = U32_SIZE + HEADER_SIZE;
 
+/// Idle buffers kept by a depth one reader, records still held by the consumer
+/// are not waited for.
+const DEPTH_ONE_POOL_SIZE: usize = 4;
+
+/// Record being read by [`AsyncDepthOneTfrecordReader`].
 pub struct RawBuffer {
-    pub io_vecs: Vec<IoVec>,
     pub offset: u64,
+    /// `None` while reading the first header
+    data: Option<PooledBuffer>,
+    tail: Box<[u8; TAIL_SIZE]>,
+    state: ReadvState,
 }
 
 impl RawBuffer {
-    pub fn build_readv_entry(&self, fd: &File, user_data: u64) -> io_uring::squeue::Entry {
-        opcode::Readv::new(
-            types::Fd(fd.as_raw_fd()),
-            self.io_vecs.as_ptr() as *const _,
-            self.io_vecs.len() as _,
-        )
-        .offset(self.offset)
-        .build()
-        .user_data(user_data)
+    pub fn new(offset: u64) -> Self {
+        Self {
+            offset,
+            data: None,
+            tail: Box::new([0; TAIL_SIZE]),
+            state: ReadvState::default(),
+        }
+    }
+
+    /// Read the header, or data, crc and the next header, or the rest after a short read.
+    pub fn build_readv_entry(&mut self, fd: &File, user_data: u64) -> io_uring::squeue::Entry {
+        let fd = types::Fd(fd.as_raw_fd());
+        let (crc, header) = self.tail.split_at_mut(U32_SIZE);
+        match self.data.as_mut() {
+            None => self
+                .state
+                .build_readv_entry(fd, &[IoVec::new(header)], self.offset, user_data),
+            Some(data) => self.state.build_readv_entry(
+                fd,
+                &[IoVec::new(data), IoVec::new(crc), IoVec::new(header)],
+                self.offset,
+                user_data,
+            ),
+        }
     }
 
     pub fn is_read_header(&self) -> bool {
-        self.io_vecs.len() == 2
+        self.data.is_none()
+    }
+
+    fn total_length(&self) -> usize {
+        match &self.data {
+            None => HEADER_SIZE,
+            Some(data) => data.len() + TAIL_SIZE,
+        }
+    }
+
+    /// Copy a read from the `O_DIRECT` blocks to the data and tail.
+    fn copy_from(&mut self, bytes: &[u8]) {
+        match self.data.as_mut() {
+            None => scatter(bytes, &mut [&mut self.tail[U32_SIZE..]]),
+            Some(data) => scatter(bytes, &mut [data, &mut self.tail[..]]),
+        }
     }
 }
 
 pub struct Buffer {
-    pub data: Vec<u8>,
+    pub data: PooledBuffer,
     pub offset: u64,
 }
 
@@ -56,6 +103,37 @@ impl Ord for Buffer {
     }
 }
 
+/// A chunk of the file being read by [`io_uring_loop`].
+struct Chunk {
+    data: PooledBuffer,
+    offset: u64,
+    state: ReadvState,
+}
+
+impl Chunk {
+    fn new(data: PooledBuffer, offset: u64) -> Self {
+        Self {
+            data,
+            offset,
+            state: ReadvState::default(),
+        }
+    }
+
+    fn build_readv_entry(&mut self, fd: &File, user_data: u64) -> io_uring::squeue::Entry {
+        self.state.build_readv_entry(
+            types::Fd(fd.as_raw_fd()),
+            &[IoVec::new(&mut self.data)],
+            self.offset,
+            user_data,
+        )
+    }
+}
+
+/// An I/O error stops the loop, reads in flight are waited for and the error is returned.
+///
+/// Chunks are recycled once the consumer drops them. Reads go through the page cache,
+/// the chunks are pooled buffers without the alignment `O_DIRECT` needs.
+/// [`AsyncDepthOneTfrecordReader::set_direct_io`] reads records with `O_DIRECT`.
 pub fn io_uring_loop<F>(file: File, queue_depth: u32, buf_size: usize, cb: F) -> Result<()>
 where
     F: Fn(Buffer),
@@ -63,21 +141,20 @@ where
     let mut ring = IoUring::new(queue_depth)?;
 
     let max_reads = queue_depth as usize;
+    let pool = BufferPool::new(max_reads * 2);
     let mut buffers = Slab::with_capacity(max_reads);
     let mut pending = Vec::with_capacity(max_reads);
     let mut heap = BinaryHeap::with_capacity(max_reads);
     let mut offset = 0;
     let mut num_reads = 0;
     let mut heap_offset = 0; // for heap
+    let mut error = None;
 
     for _ in 0..max_reads {
-        let raw_buf = RawBuffer {
-            io_vecs: vec![IoVec::from(vec![0; buf_size])],
-            offset: offset,
-        };
+        let chunk = Chunk::new(pool.get(buf_size), offset);
         offset += buf_size as u64;
-        let buf_idx = buffers.insert(raw_buf);
-        let buf_ref = &buffers[buf_idx];
+        let buf_idx = buffers.insert(chunk);
+        let buf_ref: &mut Chunk = &mut buffers[buf_idx];
         let read_e = buf_ref.build_readv_entry(&file, buf_idx as _);
         pending.push(read_e);
     }
@@ -90,17 +167,39 @@ where
     }
 
     loop {
-        ring.submit_and_wait(1)?;
-        for cqe in ring.completion() {
-            assert!(cqe.result() >= 0);
+        if let Err(err) = submit_and_wait(&mut ring, 1) {
+            // the kernel may still write into them
+            std::mem::forget(buffers);
+            return Err(err);
+        }
+        let completed: Vec<(u64, i32)> = ring
+            .completion()
+            .map(|cqe| (cqe.user_data(), cqe.result()))
+            .collect();
+        for (user_data, result) in completed {
+            let buf_idx = user_data as usize;
+            let buf_ref: &mut Chunk = &mut buffers[buf_idx];
+            let bytes_read = match check_cqe(result, None, buf_ref.offset) {
+                Ok(bytes_read) => bytes_read,
+                Err(err) => {
+                    error.get_or_insert(err);
+                    0
+                }
+            };
+            num_reads -= 1;
 
-            let buf_idx = cqe.user_data() as usize;
-            let bytes_read = cqe.result() as usize;
+            if error.is_none() && !buf_ref.state.advance(bytes_read, buf_size) {
+                // a short read before the end of the file, read the rest
+                let read_e = buf_ref.build_readv_entry(&file, buf_idx as _);
+                pending.push(read_e);
+                continue;
+            }
+            let filled = buf_ref.state.finish();
 
-            // if 0, do nothing
-            if bytes_read > 0 {
-                let buf_ref = &mut buffers[buf_idx];
-                let data = buf_ref.io_vecs[0].as_slice()[..bytes_read].to_vec();
+            // if 0 or after an error, do nothing
+            if filled > 0 && error.is_none() {
+                let mut data = std::mem::replace(&mut buf_ref.data, pool.get(buf_size));
+                data.truncate(filled);
 
                 let new_buffer = Buffer {
                     data,
@@ -114,8 +213,6 @@ where
                 let read_e = buf_ref.build_readv_entry(&file, buf_idx as _);
                 pending.push(read_e);
             }
-
-            num_reads -= 1;
         }
 
         for read_e in pending.drain(..) {
@@ -125,6 +222,12 @@ where
             num_reads += 1;
         }
 
+        if num_reads == 0 {
+            if let Some(err) = error {
+                return Err(err);
+            }
+        }
+
         ring.submit()?;
 
         while let Some(Reverse(ref buf_ref)) = heap.peek() {
@@ -161,7 +264,7 @@ where
             source,
             offset: 0,
             buf: Buffer {
-                data: Vec::new(),
+                data: PooledBuffer::from(Vec::new()),
                 offset: 0,
             },
             is_end: false,
@@ -223,125 +326,333 @@ pub struct AsyncDepthOneTfrecordReader {
     pub file: File,
     pub raw_buffer: RawBuffer,
     pub ring: IoUring,
+    pool: BufferPool,
     check_integrity: bool,
+    max_record_length: u64,
+    path: Option<PathBuf>,
+    is_started: bool,
+    is_end: bool,
+    /// A read into `raw_buffer`, or `window`, is submitted and its completion not taken yet
+    is_reading: bool,
+    /// Blocks read with `O_DIRECT`
+    window: Option<DirectWindow>,
 }
 
 impl AsyncDepthOneTfrecordReader {
+    /// Same as [`AsyncDepthOneTfrecordReader::new`], but errors name the file.
+    pub fn open<P: AsRef<Path>>(path: P, check_integrity: bool) -> Result<Self> {
+        let mut reader = Self::new(File::open(&path)?, check_integrity)?;
+        reader.path = Some(path.as_ref().to_owned());
+        Ok(reader)
+    }
+
     pub fn new(file: File, check_integrity: bool) -> Result<Self> {
         let ring = IoUring::new(1)?;
         Ok(Self {
             file,
-            raw_buffer: RawBuffer {
-                io_vecs: Vec::new(),
-                offset: 0,
-            },
+            raw_buffer: RawBuffer::new(0),
             ring,
+            pool: BufferPool::new(DEPTH_ONE_POOL_SIZE),
             check_integrity,
+            max_record_length: DEFAULT_MAX_RECORD_LENGTH,
+            path: None,
+            is_started: false,
+            is_end: false,
+            is_reading: false,
+            window: None,
         })
     }
 
+    /// Read the file in aligned blocks through a descriptor of our own opened with
+    /// `O_DIRECT`, see [`ReadOptions::direct_io`](crate::async_reader::ReadOptions::direct_io).
+    /// Must be called before the first read.
+    pub fn set_direct_io(&mut self, direct_io: bool) -> Result<()> {
+        if self.is_started {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "direct_io must be set before the first read",
+            )
+            .into());
+        }
+        if direct_io && self.window.is_none() {
+            self.file = reopen_direct(&self.file)?;
+            self.window = Some(DirectWindow::new());
+        }
+        Ok(())
+    }
+
+    /// A header with a larger data length is treated as corrupted instead of allocating for it.
+    pub fn set_max_record_length(&mut self, max_record_length: u64) {
+        self.max_record_length = max_record_length;
+    }
+
     pub fn is_started(&self) -> bool {
-        !self.raw_buffer.io_vecs.is_empty()
+        self.is_started
     }
 
     pub fn start(&mut self) -> Result<()> {
-        self.raw_buffer.io_vecs = vec![
-            IoVec::from(vec![0; U64_SIZE]), // length
-            IoVec::from(vec![0; U32_SIZE]), // crc_of_length
-        ];
-        let read_e = self.raw_buffer.build_readv_entry(&self.file, 0x42);
-        unsafe {
-            self.ring.submission().push(&read_e)?;
+        self.is_started = true;
+        self.submit()?;
+        let filled = self.wait_read()?;
+        if filled == 0 {
+            // empty file
+            self.is_end = true;
+            return Ok(());
+        }
+        if filled < HEADER_SIZE {
+            return Err(self.truncated("header", self.raw_buffer.offset));
         }
-        self.ring.submit_and_wait(1)?;
 
-        let cqe = self.ring.completion().next().unwrap();
-        assert!(cqe.result() >= 0);
-        assert!(cqe.user_data() == 0x42);
-        // dbg!(cqe.result());
+        self.next_record(self.raw_buffer.offset)
+    }
 
-        let length_buf = self.raw_buffer.io_vecs[0]
-            .as_slice()
-            .try_into()
-            .expect("fail to convert to array");
-        let length = u64::from_le_bytes(length_buf);
-        // dbg!(length);
-        if self.check_integrity {
-            let masked_crc_buf = self.raw_buffer.io_vecs[1]
-                .as_slice()
-                .try_into()
-                .expect("fail to convert to array");
-            let masked_crc = u32::from_le_bytes(masked_crc_buf);
-            assert!(verify_masked_crc(&length_buf, masked_crc).is_ok());
-        }
-
-        self.raw_buffer.io_vecs = vec![
-            IoVec::from(vec![0; length as usize]), // data
-            IoVec::from(vec![0; U32_SIZE]),        // crc of data
-            IoVec::from(vec![0; U64_SIZE]),        // length
-            IoVec::from(vec![0; U32_SIZE]),
-        ];
-        self.raw_buffer.offset += (U32_SIZE + U64_SIZE) as u64;
-
-        let read_e = self.raw_buffer.build_readv_entry(&self.file, 0x42);
-        unsafe {
-            self.ring.submission().push(&read_e)?;
+    /// Errors are not recoverable, the next read returns `None`.
+    pub fn read(&mut self) -> Result<Option<PooledBuffer>> {
+        if self.is_end {
+            return Ok(None);
         }
-        self.ring.submit()?;
-
-        Ok(())
+        let result = self.read_next();
+        if result.is_err() {
+            self.is_end = true;
+        }
+        result
     }
 
-    pub fn read(&mut self) -> Result<Option<Vec<u8>>> {
+    fn read_next(&mut self) -> Result<Option<PooledBuffer>> {
         if !self.is_started() {
             self.start()?;
-        }
-        self.ring.submit_and_wait(1)?;
-        let cqe = self.ring.completion().next().unwrap();
-        assert!(cqe.result() >= 0);
-        // dbg!(cqe.result());
-
-        let bytes_read = cqe.result() as usize;
-        if bytes_read == 0 {
-            Ok(None)
-        } else {
-            let data_buf = Vec::from(self.raw_buffer.io_vecs[0]);
-            if self.check_integrity {
-                let masked_crc_buf = self.raw_buffer.io_vecs[1].as_slice().try_into().unwrap();
-                let masked_crc = u32::from_le_bytes(masked_crc_buf);
-                verify_masked_crc(&data_buf, masked_crc)?;
+            if self.is_end {
+                return Ok(None);
             }
+        }
 
-            let data_length = data_buf.len();
+        let filled = self.wait_read()?;
+        let record_offset = self.raw_buffer.offset - HEADER_SIZE as u64;
+        let data_buf = self.raw_buffer.data.take().expect("reading a record");
+        let record_end = data_buf.len() + U32_SIZE;
+        if filled < record_end {
+            return Err(self.truncated("record", record_offset));
+        }
 
-            let length_buf = self.raw_buffer.io_vecs[2].as_slice().try_into().unwrap();
-            let length = u64::from_le_bytes(length_buf);
+        if self.check_integrity {
+            let crc_buf = &self.raw_buffer.tail[..U32_SIZE];
+            verify_crc(&data_buf, crc_buf, self.path.as_deref(), record_offset)?;
+        }
 
-            if self.check_integrity {
-                let masked_crc_buf = self.raw_buffer.io_vecs[3].as_slice().try_into().unwrap();
-                let masked_crc = u32::from_le_bytes(masked_crc_buf);
-                verify_masked_crc(&length_buf, masked_crc)?;
+        let next_offset = self.raw_buffer.offset + record_end as u64;
+        if filled == record_end {
+            // the last record
+            self.is_end = true;
+            return Ok(Some(data_buf));
+        }
+        if filled < record_end + HEADER_SIZE {
+            return Err(self.truncated("header", next_offset));
+        }
+
+        self.next_record(next_offset)?;
+        Ok(Some(data_buf))
+    }
+
+    /// Check the header read in the tail and submit the read of its record.
+    fn next_record(&mut self, header_offset: u64) -> Result<()> {
+        let header = &self.raw_buffer.tail[U32_SIZE..];
+        let length = read_u64(header);
+        if self.check_integrity {
+            verify_crc(
+                &header[..U64_SIZE],
+                &header[U64_SIZE..],
+                self.path.as_deref(),
+                header_offset,
+            )?;
+        }
+        check_read_length(
+            length,
+            self.max_record_length,
+            self.path.as_deref(),
+            header_offset,
+        )?;
+
+        self.raw_buffer.data = Some(self.pool.get(length as usize));
+        self.raw_buffer.offset = header_offset + HEADER_SIZE as u64;
+
+        self.submit()?;
+        self.ring.submit()?;
+        Ok(())
+    }
+
+    /// Submit the read of `raw_buffer`, with `O_DIRECT` only if its blocks weren't read
+    /// ahead with an earlier record.
+    fn submit(&mut self) -> Result<()> {
+        if let Some(window) = self.window.as_mut() {
+            let total_length = self.raw_buffer.total_length();
+            if window.get(self.raw_buffer.offset, total_length).is_some() {
+                return Ok(());
             }
+            window.request(self.raw_buffer.offset, total_length, DIRECT_READ_AHEAD);
+        }
+        self.push_read()
+    }
 
-            // Reset data buffer
-            self.raw_buffer.io_vecs[0] = IoVec::from(vec![0; length as usize]);
-            self.raw_buffer.offset += (data_length + U32_SIZE + U64_SIZE + U32_SIZE) as u64;
+    /// Push the read of `raw_buffer` or the window, or the rest of it after a short read.
+    fn push_read(&mut self) -> Result<()> {
+        let read_e = match self.window.as_mut() {
+            Some(window) => window.build_read_entry(types::Fd(self.file.as_raw_fd()), 0x42),
+            None => self.raw_buffer.build_readv_entry(&self.file, 0x42),
+        };
+        unsafe {
+            self.ring.submission().push(&read_e)?;
+        }
+        self.is_reading = true;
+        Ok(())
+    }
 
-            let read_e = self.raw_buffer.build_readv_entry(&self.file, 0x42);
-            unsafe {
-                self.ring.submission().push(&read_e)?;
+    /// Wait for the read in flight and the rest of it after short reads, return
+    /// the bytes filled, which are less than asked only at the end of the file.
+    fn wait_read(&mut self) -> Result<usize> {
+        while self.is_reading {
+            submit_and_wait(&mut self.ring, 1)?;
+            let cqe = self.ring.completion().next().expect("one read in flight");
+            self.is_reading = false;
+            debug_assert_eq!(cqe.user_data(), 0x42);
+            let bytes_read = check_cqe(cqe.result(), self.path.as_deref(), self.raw_buffer.offset)?;
+
+            let done = match self.window.as_mut() {
+                Some(window) => window.on_read(bytes_read),
+                None => {
+                    let total = self.raw_buffer.total_length();
+                    self.raw_buffer.state.advance(bytes_read, total)
+                }
+            };
+            if !done {
+                self.push_read()?;
+            }
+        }
+
+        match self.window.as_ref() {
+            Some(window) => {
+                let total = self.raw_buffer.total_length();
+                let bytes = window
+                    .get(self.raw_buffer.offset, total)
+                    .expect("blocks read");
+                self.raw_buffer.copy_from(bytes);
+                Ok(bytes.len())
             }
-            self.ring.submit()?;
+            None => Ok(self.raw_buffer.state.finish()),
+        }
+    }
 
-            Ok(Some(data_buf))
+    fn truncated(&self, what: &str, offset: u64) -> Error {
+        truncated(self.path.as_deref(), what, offset)
+    }
+}
+
+impl Drop for AsyncDepthOneTfrecordReader {
+    /// Wait for the read of the next record, which the kernel may still write into
+    /// `raw_buffer` or the window, when the reader is dropped early.
+    fn drop(&mut self) {
+        if self.is_reading && submit_and_wait(&mut self.ring, 1).is_err() {
+            std::mem::forget(std::mem::replace(&mut self.raw_buffer, RawBuffer::new(0)));
+            std::mem::forget(self.window.take());
         }
     }
 }
 
 impl Iterator for AsyncDepthOneTfrecordReader {
-    type Item = Result<Vec<u8>>;
+    type Item = Result<PooledBuffer>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.read().transpose()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_writer::TfrecordWriter;
+
+    #[test]
+    fn depth_one_end_and_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let mut writer = TfrecordWriter::create(&path).unwrap();
+        let records: Vec<Vec<u8>> = (0..5).map(|i| vec![i; 10 + i as usize]).collect();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let reader = AsyncDepthOneTfrecordReader::open(&path, true).unwrap();
+        let decoded: Vec<Vec<u8>> = reader.map(|record| record.unwrap().into_vec()).collect();
+        assert_eq!(decoded, records);
+
+        // dropped while reading the next record
+        let mut reader = AsyncDepthOneTfrecordReader::open(&path, true).unwrap();
+        assert_eq!(reader.read().unwrap().as_deref(), Some(&records[0][..]));
+        assert!(reader.is_reading);
+        drop(reader);
+
+        let empty = dir.path().join("empty.tfrecord");
+        std::fs::write(&empty, b"").unwrap();
+        let mut reader = AsyncDepthOneTfrecordReader::open(&empty, true).unwrap();
+        assert!(reader.read().unwrap().is_none());
+
+        let buf = std::fs::read(&path).unwrap();
+        for cut in [3, 10] {
+            std::fs::write(&path, &buf[..buf.len() - cut]).unwrap();
+            let mut reader = AsyncDepthOneTfrecordReader::open(&path, true).unwrap();
+            for record in &records[..4] {
+                assert_eq!(reader.read().unwrap().as_deref(), Some(&record[..]));
+            }
+            assert!(matches!(reader.read(), Err(Error::DataLoss(_))));
+            assert!(reader.read().unwrap().is_none());
+        }
+    }
+
+    #[test]
+    fn depth_one_direct_io() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let mut writer = TfrecordWriter::create(&path).unwrap();
+        // small records share blocks, large ones span the read ahead
+        let records: Vec<Vec<u8>> = (0..200)
+            .map(|i| vec![i as u8; if i % 50 == 7 { 300_000 } else { i * 13 }])
+            .collect();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let read = |file: File| {
+            let mut reader = AsyncDepthOneTfrecordReader::new(file, true).unwrap();
+            reader.set_direct_io(true).unwrap();
+            reader
+        };
+
+        // the file of the caller is left without O_DIRECT
+        let file = File::open(&path).unwrap();
+        let clone = file.try_clone().unwrap();
+        let decoded: Vec<Vec<u8>> = read(file)
+            .map(|record| record.unwrap().into_vec())
+            .collect();
+        assert_eq!(decoded, records);
+        let flags = unsafe { libc::fcntl(clone.as_raw_fd(), libc::F_GETFL) };
+        assert_eq!(flags & libc::O_DIRECT, 0);
+
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &buf[..buf.len() - 3]).unwrap();
+        let mut reader = read(File::open(&path).unwrap());
+        for record in &records[..199] {
+            assert_eq!(reader.read().unwrap().as_deref(), Some(&record[..]));
+        }
+        assert!(matches!(reader.read(), Err(Error::DataLoss(_))));
+
+        std::fs::write(&path, b"").unwrap();
+        assert!(read(File::open(&path).unwrap()).read().unwrap().is_none());
+
+        let mut reader = read(File::open(&path).unwrap());
+        reader.read().unwrap();
+        assert!(reader.set_direct_io(true).is_err());
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    fmt,
+    ops::{Deref, DerefMut},
+    sync::{Arc, Mutex, Weak},
+};
+
+type Buffers = Mutex<Vec<Vec<u8>>>;
+
+/// Recycle the buffers that io_uring reads into.
+///
+/// A [`PooledBuffer`] goes back to its pool when dropped, so a reader handing records
+/// to consumers stops allocating once the pool is warm. Cloning shares the pool.
+#[derive(Clone)]
+pub struct BufferPool {
+    buffers: Arc<Buffers>,
+    max_buffers: usize,
+}
+
+impl BufferPool {
+    /// Keep at most `max_buffers` idle buffers, more are freed when dropped.
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            buffers: Arc::new(Mutex::new(Vec::with_capacity(max_buffers))),
+            max_buffers,
+        }
+    }
+
+    /// A buffer of `len` bytes, the content of a recycled buffer is left over from
+    /// its last use and meant to be overwritten.
+    pub fn get(&self, len: usize) -> PooledBuffer {
+        let mut buf = self.buffers.lock().unwrap().pop().unwrap_or_default();
+        buf.resize(len, 0);
+        PooledBuffer {
+            buf,
+            pool: Some((Arc::downgrade(&self.buffers), self.max_buffers)),
+        }
+    }
+
+    /// Number of idle buffers.
+    pub fn len(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl fmt::Debug for BufferPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BufferPool")
+            .field("idle", &self.len())
+            .field("max_buffers", &self.max_buffers)
+            .finish()
+    }
+}
+
+/// Owned bytes of a [`BufferPool`], returned to the pool on drop if it still exists.
+pub struct PooledBuffer {
+    buf: Vec<u8>,
+    pool: Option<(Weak<Buffers>, usize)>,
+}
+
+impl PooledBuffer {
+    pub fn truncate(&mut self, len: usize) {
+        self.buf.truncate(len);
+    }
+
+    /// Take the bytes out of the pool.
+    pub fn into_vec(mut self) -> Vec<u8> {
+        self.pool = None;
+        std::mem::take(&mut self.buf)
+    }
+}
+
+/// A buffer that belongs to no pool.
+impl From<Vec<u8>> for PooledBuffer {
+    fn from(buf: Vec<u8>) -> Self {
+        Self { buf, pool: None }
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+impl AsRef<[u8]> for PooledBuffer {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl fmt::Debug for PooledBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PooledBuffer")
+            .field("len", &self.buf.len())
+            .finish()
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        if let Some((pool, max_buffers)) = self.pool.take() {
+            if let Some(buffers) = pool.upgrade() {
+                let mut buffers = buffers.lock().unwrap();
+                if buffers.len() < max_buffers {
+                    buffers.push(std::mem::take(&mut self.buf));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recycle() {
+        let pool = BufferPool::new(1);
+        let mut buf = pool.get(10);
+        buf[0] = 42;
+        let ptr = buf.as_ptr();
+        let other = pool.get(3);
+        drop(buf);
+        drop(other);
+        assert_eq!(pool.len(), 1);
+
+        let buf = pool.get(4);
+        assert_eq!(buf.as_ptr(), ptr);
+        assert_eq!(buf.len(), 4);
+        assert_eq!(buf.into_vec().len(), 4);
+        assert!(pool.is_empty());
+
+        let buf = pool.get(4);
+        drop(pool);
+        drop(buf);
+    }
+}
@@ -0,0 +1,576 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    mem,
+    os::raw::c_int,
+    path::Path,
+    ptr,
+};
+
+use flate2::bufread::{MultiGzDecoder, ZlibDecoder};
+use libz_sys::{
+    deflate, deflateEnd, deflateInit2_, uInt, voidpf, z_stream, zlibVersion, Z_BUF_ERROR,
+    Z_DEFAULT_COMPRESSION, Z_DEFAULT_STRATEGY, Z_DEFLATED, Z_FINISH, Z_NO_FLUSH, Z_OK,
+    Z_PARTIAL_FLUSH, Z_STREAM_END,
+};
+
+use crate::{
+    constants::{U32_SIZE, U64_SIZE},
+    crc32c::get_masked_crc,
+    error::{Error, Result},
+};
+
+/// `ZlibCompressionOptions` of TensorFlow's writer, flate2 always deflates with
+/// `mem_level` 8 and can't produce the same bytes.
+const ZLIB_WINDOW_BITS: c_int = 15;
+const ZLIB_MEM_LEVEL: c_int = 9;
+const ZLIB_BUFFER_SIZE: usize = 256 << 10;
+/// Added to the window bits for a gzip header and trailer instead of zlib's.
+const GZIP_WINDOW_BITS: c_int = 16;
+
+const ZSTD_LEVEL: i32 = 3;
+
+/// Stream level compression, same as `compression_type` of `tf.io.TFRecordWriter`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zlib,
+}
+
+impl Compression {
+    /// Guess compression from file extension, `.gz` for GZIP and `.zz`/`.zlib` for ZLIB.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        match path.as_ref().extension()?.to_str()? {
+            "gz" | "gzip" => Some(Self::Gzip),
+            "zz" | "zlib" => Some(Self::Zlib),
+            _ => None,
+        }
+    }
+
+    /// Guess compression from the first bytes of a file.
+    ///
+    /// A valid uncompressed record header wins over the magic bytes,
+    /// because a record length may start with the same bytes as a gzip or zlib header.
+    pub fn from_magic(buf: &[u8]) -> Self {
+        if buf.len() >= U64_SIZE + U32_SIZE {
+            let length_buf = &buf[..U64_SIZE];
+            let masked_crc =
+                u32::from_le_bytes(buf[U64_SIZE..U64_SIZE + U32_SIZE].try_into().unwrap());
+            if get_masked_crc(length_buf) == masked_crc {
+                return Self::None;
+            }
+        }
+
+        match buf {
+            [0x1f, 0x8b, ..] => Self::Gzip,
+            [cmf, flg, ..]
+                if cmf & 0x0f == 8
+                    && cmf >> 4 <= 7
+                    && u16::from_be_bytes([*cmf, *flg]) % 31 == 0 =>
+            {
+                Self::Zlib
+            }
+            _ => Self::None,
+        }
+    }
+
+    /// Peek the reader without consuming it and guess compression from magic bytes.
+    pub fn detect<R: BufRead>(reader: &mut R) -> Result<Self> {
+        let buf = reader.fill_buf()?;
+        Ok(Self::from_magic(buf))
+    }
+
+    /// Use file extension first, then fall back to magic bytes.
+    pub fn detect_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        if let Some(compression) = Self::from_path(&path) {
+            return Ok(compression);
+        }
+        let mut reader = BufReader::new(File::open(path)?);
+        Self::detect(&mut reader)
+    }
+}
+
+pub enum Decoder<R> {
+    None(R),
+    Gzip(MultiGzDecoder<R>),
+    Zlib(ZlibDecoder<R>),
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn new(reader: R, compression: Compression) -> Self {
+        match compression {
+            Compression::None => Self::None(reader),
+            Compression::Gzip => Self::Gzip(MultiGzDecoder::new(reader)),
+            Compression::Zlib => Self::Zlib(ZlibDecoder::new(reader)),
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        match self {
+            Self::None(_) => Compression::None,
+            Self::Gzip(_) => Compression::Gzip,
+            Self::Zlib(_) => Compression::Zlib,
+        }
+    }
+}
+
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::None(reader) => reader.read(buf),
+            Self::Gzip(reader) => reader.read(buf),
+            Self::Zlib(reader) => reader.read(buf),
+        }
+    }
+}
+
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(ZlibWriter<W>),
+    Zlib(ZlibWriter<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Same bytes as `tf.io.TFRecordWriter` with the same `compression_type`.
+    pub fn new(writer: W, compression: Compression) -> Self {
+        match compression {
+            Compression::None => Self::None(writer),
+            Compression::Gzip => Self::Gzip(ZlibWriter::new(writer, true)),
+            Compression::Zlib => Self::Zlib(ZlibWriter::new(writer, false)),
+        }
+    }
+
+    pub fn compression(&self) -> Compression {
+        match self {
+            Self::None(_) => Compression::None,
+            Self::Gzip(_) => Compression::Gzip,
+            Self::Zlib(_) => Compression::Zlib,
+        }
+    }
+
+    /// Write the stream trailer, no more data can be written after this.
+    pub fn try_finish(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::None(_) => Ok(()),
+            Self::Gzip(encoder) => encoder.try_finish(),
+            Self::Zlib(encoder) => encoder.try_finish(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        match self {
+            Self::None(writer) => writer,
+            Self::Gzip(encoder) => encoder.get_mut(),
+            Self::Zlib(encoder) => encoder.get_mut(),
+        }
+    }
+
+    /// Write the stream trailer and return the inner writer.
+    pub fn finish(self) -> Result<W> {
+        let writer = match self {
+            Self::None(writer) => writer,
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zlib(encoder) => encoder.finish()?,
+        };
+        Ok(writer)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::None(writer) => writer.write(buf),
+            Self::Gzip(writer) => writer.write(buf),
+            Self::Zlib(writer) => writer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::None(writer) => writer.flush(),
+            Self::Gzip(writer) => writer.flush(),
+            Self::Zlib(writer) => writer.flush(),
+        }
+    }
+}
+
+/// zlib deflate stream with the settings and flush modes of TensorFlow's
+/// `ZlibOutputBuffer`, gzip framing uses zlib's default header with zero mtime.
+pub struct ZlibWriter<W: Write> {
+    // zlib keeps a pointer back to the stream, so it must not move
+    stream: Box<z_stream>,
+    writer: Option<W>,
+    buf: Vec<u8>,
+    finished: bool,
+}
+
+// the stream is only used through `&mut self`
+unsafe impl<W: Write + Send> Send for ZlibWriter<W> {}
+unsafe impl<W: Write + Sync> Sync for ZlibWriter<W> {}
+
+unsafe extern "C" fn zalloc(_opaque: voidpf, items: uInt, size: uInt) -> voidpf {
+    libc::calloc(items as usize, size as usize)
+}
+
+unsafe extern "C" fn zfree(_opaque: voidpf, address: voidpf) {
+    libc::free(address)
+}
+
+impl<W: Write> ZlibWriter<W> {
+    pub fn new(writer: W, gzip: bool) -> Self {
+        let mut stream = Box::new(z_stream {
+            next_in: ptr::null_mut(),
+            avail_in: 0,
+            total_in: 0,
+            next_out: ptr::null_mut(),
+            avail_out: 0,
+            total_out: 0,
+            msg: ptr::null_mut(),
+            state: ptr::null_mut(),
+            zalloc,
+            zfree,
+            opaque: ptr::null_mut(),
+            data_type: 0,
+            adler: 0,
+            reserved: 0,
+        });
+        let window_bits = if gzip {
+            ZLIB_WINDOW_BITS + GZIP_WINDOW_BITS
+        } else {
+            ZLIB_WINDOW_BITS
+        };
+        let ret = unsafe {
+            deflateInit2_(
+                &mut *stream,
+                Z_DEFAULT_COMPRESSION,
+                Z_DEFLATED,
+                window_bits,
+                ZLIB_MEM_LEVEL,
+                Z_DEFAULT_STRATEGY,
+                zlibVersion(),
+                mem::size_of::<z_stream>() as c_int,
+            )
+        };
+        // only fails on invalid parameters or out of memory
+        assert_eq!(ret, Z_OK, "deflateInit2 failed");
+
+        Self {
+            stream,
+            writer: Some(writer),
+            buf: vec![0; ZLIB_BUFFER_SIZE],
+            finished: false,
+        }
+    }
+
+    /// Write the stream trailer, no more data can be written after this.
+    pub fn try_finish(&mut self) -> std::io::Result<()> {
+        if !self.finished {
+            self.deflate(&[], Z_FINISH)?;
+            self.finished = true;
+        }
+        Ok(())
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        self.writer.as_mut().unwrap()
+    }
+
+    /// Write the stream trailer and return the inner writer.
+    pub fn finish(mut self) -> std::io::Result<W> {
+        self.try_finish()?;
+        Ok(self.writer.take().unwrap())
+    }
+
+    /// Deflate all of `input` and write out what zlib produced. Unless `flush` is
+    /// `Z_NO_FLUSH`, returns once zlib has nothing pending.
+    fn deflate(&mut self, mut input: &[u8], flush: c_int) -> std::io::Result<()> {
+        let writer = self.writer.as_mut().unwrap();
+        loop {
+            let chunk = input.len().min(uInt::MAX as usize);
+            let mode = if chunk < input.len() {
+                Z_NO_FLUSH
+            } else {
+                flush
+            };
+            self.stream.next_in = input.as_ptr() as *mut u8;
+            self.stream.avail_in = chunk as uInt;
+            self.stream.next_out = self.buf.as_mut_ptr();
+            self.stream.avail_out = self.buf.len() as uInt;
+            let ret = unsafe { deflate(&mut *self.stream, mode) };
+
+            input = &input[chunk - self.stream.avail_in as usize..];
+            let produced = self.buf.len() - self.stream.avail_out as usize;
+            writer.write_all(&self.buf[..produced])?;
+            match ret {
+                Z_STREAM_END => return Ok(()),
+                // no progress, e.g. a second flush without new input
+                Z_OK | Z_BUF_ERROR => {}
+                _ => {
+                    return Err(std::io::Error::other(format!(
+                        "deflate failed with code {ret}"
+                    )))
+                }
+            }
+            // a full output buffer may leave output pending
+            if input.is_empty() && produced < self.buf.len() && flush != Z_FINISH {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for ZlibWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.deflate(buf, Z_NO_FLUSH)?;
+        Ok(buf.len())
+    }
+
+    /// Ends the current block with `Z_PARTIAL_FLUSH` like TensorFlow's `Flush`.
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.finished {
+            self.deflate(&[], Z_PARTIAL_FLUSH)?;
+        }
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write> Drop for ZlibWriter<W> {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = self.try_finish();
+        }
+        unsafe { deflateEnd(&mut *self.stream) };
+    }
+}
+
+/// Per record compression.
+///
+/// The payload of every record is prefixed with one flag byte, so each record keeps
+/// its own tfrecord header and CRC and can still be addressed through the index.
+/// Files written this way are only readable with `set_record_compression(true)`
+/// or by passing payloads through [`RecordCompression::decompress`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum RecordCompression {
+    #[default]
+    None = 0,
+    Zstd = 1,
+    Lz4 = 2,
+}
+
+impl RecordCompression {
+    pub fn from_flag(flag: u8) -> Result<Self> {
+        match flag {
+            0 => Ok(Self::None),
+            1 => Ok(Self::Zstd),
+            2 => Ok(Self::Lz4),
+            _ => Err(Error::DataLoss(format!(
+                "unknown record compression flag {flag}"
+            ))),
+        }
+    }
+
+    /// Compress a payload and prefix the flag byte.
+    ///
+    /// Falls back to [`RecordCompression::None`] if compression doesn't make it smaller.
+    pub fn compress(self, buf: &[u8]) -> Result<Vec<u8>> {
+        let mut out = vec![self as u8];
+        match self {
+            Self::None => out.extend_from_slice(buf),
+            Self::Zstd => zstd::stream::copy_encode(buf, &mut out, ZSTD_LEVEL)?,
+            Self::Lz4 => {
+                let size = u32::try_from(buf.len()).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("record of {} bytes is too large for lz4", buf.len()),
+                    )
+                })?;
+                out.extend_from_slice(&size.to_le_bytes());
+                out.resize(
+                    out.len() + lz4_flex::block::get_maximum_output_size(buf.len()),
+                    0,
+                );
+                let n = lz4_flex::compress_into(buf, &mut out[1 + U32_SIZE..])
+                    .map_err(|err| Error::DataLoss(err.to_string()))?;
+                out.truncate(1 + U32_SIZE + n);
+            }
+        }
+
+        if out.len() > buf.len() + 1 {
+            return Self::None.compress(buf);
+        }
+        Ok(out)
+    }
+
+    /// Read the flag byte and decompress the rest of the payload, a record larger than
+    /// `max_length` once decompressed is treated as corrupted.
+    pub fn decompress(buf: &[u8], max_length: u64) -> Result<Vec<u8>> {
+        let mut out = Vec::new();
+        Self::decompress_into(buf, &mut out, max_length)?;
+        Ok(out)
+    }
+
+    /// Same as [`RecordCompression::decompress`], but reuse `out`.
+    pub fn decompress_into(buf: &[u8], out: &mut Vec<u8>, max_length: u64) -> Result<()> {
+        let (flag, data) = buf
+            .split_first()
+            .ok_or_else(|| Error::DataLoss("missing record compression flag".to_string()))?;
+        out.clear();
+        match Self::from_flag(*flag)? {
+            Self::None => out.extend_from_slice(data),
+            Self::Zstd => {
+                // one byte more than allowed tells a too large record apart
+                zstd::stream::read::Decoder::new(data)?
+                    .take(max_length.saturating_add(1))
+                    .read_to_end(out)?;
+                check_decompressed_length(out.len() as u64, max_length)?;
+            }
+            Self::Lz4 => {
+                let (size, data) = lz4_flex::block::uncompressed_size(data)
+                    .map_err(|err| Error::DataLoss(err.to_string()))?;
+                check_decompressed_length(size as u64, max_length)?;
+                out.resize(size, 0);
+                let n = lz4_flex::decompress_into(data, out)
+                    .map_err(|err| Error::DataLoss(err.to_string()))?;
+                out.truncate(n);
+            }
+        }
+        Ok(())
+    }
+}
+
+fn check_decompressed_length(length: u64, max_length: u64) -> Result<()> {
+    if length > max_length {
+        return Err(Error::DataLoss(format!(
+            "decompressed record length exceeds the limit {max_length}"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::{sync_reader::TfrecordReader, sync_writer::TfrecordWriter};
+
+    fn round_trip(compression: Compression) {
+        let records: Vec<Vec<u8>> = (0..100).map(|i| vec![i as u8; i * 10]).collect();
+
+        let mut writer = TfrecordWriter::new(Encoder::new(Vec::new(), compression));
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        let buf = writer.finish().unwrap().finish().unwrap();
+
+        let mut cursor = Cursor::new(&buf);
+        assert_eq!(Compression::detect(&mut cursor).unwrap(), compression);
+
+        let reader = TfrecordReader::with_compression(Cursor::new(&buf), compression, true);
+        let decoded: Vec<Vec<u8>> = reader.map(|record| record.unwrap()).collect();
+        assert_eq!(decoded, records);
+    }
+
+    #[test]
+    fn gzip_round_trip() {
+        round_trip(Compression::Gzip);
+    }
+
+    #[test]
+    fn zlib_round_trip() {
+        round_trip(Compression::Zlib);
+    }
+
+    #[test]
+    fn none_round_trip() {
+        round_trip(Compression::None);
+    }
+
+    #[test]
+    fn gzip_header_matches_zlib() {
+        let mut writer = TfrecordWriter::new(Encoder::new(Vec::new(), Compression::Gzip));
+        writer.write(b"hello").unwrap();
+        let buf = writer.finish().unwrap().finish().unwrap();
+        // zero mtime and OS_CODE 3 (unix)
+        assert_eq!(&buf[..10], &[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3]);
+    }
+
+    /// `testdata/make_compressed.py` writes the same records with `tf.io.TFRecordWriter`.
+    #[test]
+    fn matches_tensorflow_writer() {
+        let records: Vec<String> = (0..50u64)
+            .map(|i| {
+                let numbers: Vec<String> = (0..i * 10)
+                    .map(|j| (i * j * 7919 % 100003).to_string())
+                    .collect();
+                numbers.join(" ")
+            })
+            .collect();
+
+        for (compression, expected) in [
+            (
+                Compression::Gzip,
+                include_bytes!("../testdata/tensorflow.tfrecord.gz").as_slice(),
+            ),
+            (
+                Compression::Zlib,
+                include_bytes!("../testdata/tensorflow.tfrecord.zz").as_slice(),
+            ),
+        ] {
+            let mut writer = TfrecordWriter::new(Encoder::new(Vec::new(), compression));
+            for record in &records {
+                writer.write(record.as_bytes()).unwrap();
+            }
+            let buf = writer.finish().unwrap().finish().unwrap();
+            assert!(
+                buf == expected,
+                "{compression:?} output differs from TensorFlow"
+            );
+        }
+    }
+
+    #[test]
+    fn record_compression_round_trip() {
+        let records: Vec<Vec<u8>> = (0..100).map(|i| vec![i as u8; i * 10]).collect();
+
+        for record_compression in [
+            RecordCompression::None,
+            RecordCompression::Zstd,
+            RecordCompression::Lz4,
+        ] {
+            let mut writer = TfrecordWriter::new(Vec::new());
+            writer.set_record_compression(Some(record_compression));
+            for record in &records {
+                writer.write(record).unwrap();
+            }
+
+            let mut reader = TfrecordReader::new(Cursor::new(writer.into_inner()), true);
+            reader.set_record_compression(true);
+            let decoded: Vec<Vec<u8>> = reader.map(|record| record.unwrap()).collect();
+            assert_eq!(decoded, records);
+        }
+    }
+
+    #[test]
+    fn decompressed_length_is_limited() {
+        let buf = vec![7; 10_000];
+        for record_compression in [RecordCompression::Zstd, RecordCompression::Lz4] {
+            let compressed = record_compression.compress(&buf).unwrap();
+            assert_eq!(
+                RecordCompression::decompress(&compressed, 10_000).unwrap(),
+                buf
+            );
+            let result = RecordCompression::decompress(&compressed, 9_999);
+            assert!(matches!(result, Err(Error::DataLoss(_))));
+        }
+
+        // a corrupted lz4 size isn't allocated
+        let mut compressed = RecordCompression::Lz4.compress(&buf).unwrap();
+        compressed[1..1 + U32_SIZE].copy_from_slice(&u32::MAX.to_le_bytes());
+        let result = RecordCompression::decompress(&compressed, 1 << 20);
+        assert!(matches!(result, Err(Error::DataLoss(_))));
+    }
+}
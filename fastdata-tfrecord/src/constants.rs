@@ -1,2 +1,6 @@
 pub const U64_SIZE: usize = std::mem::size_of::<u64>();
 pub const U32_SIZE: usize = std::mem::size_of::<u32>();
+/// Length and masked crc of length.
+pub const HEADER_SIZE: usize = U64_SIZE + U32_SIZE;
+/// Default limit of the data length, a larger length is treated as a corrupted header.
+pub const DEFAULT_MAX_RECORD_LENGTH: u64 = 1 << 30;
@@ -14,6 +14,10 @@ pub fn verify_masked_crc(buf: &[u8], expect: u32) -> Result<()> {
     if found == expect {
         Ok(())
     } else {
-        Err(Error::ChecksumMismatch { found, expect })
+        Err(Error::ChecksumMismatch {
+            found,
+            expect,
+            context: None,
+        })
     }
 }
@@ -1,3 +1,5 @@
+use std::{fmt, path::PathBuf};
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(thiserror::Error, Debug)]
@@ -5,8 +7,15 @@ pub enum Error {
     #[error("I/O Error {0}")]
     IoError(#[from] std::io::Error),
 
-    #[error("checksum mismatch error: expect {expect:#010x}, but found {found:#010x}")]
-    ChecksumMismatch { found: u32, expect: u32 },
+    #[error(
+        "checksum mismatch error{}: expect {expect:#010x}, but found {found:#010x}",
+        context.as_ref().map(|context| format!(" in {context}")).unwrap_or_default()
+    )]
+    ChecksumMismatch {
+        found: u32,
+        expect: u32,
+        context: Option<ReadContext>,
+    },
 
     #[error("eof")]
     OutOfRange,
@@ -20,10 +29,57 @@ pub enum Error {
 
     #[error("{0}")]
     DecodeError(#[from] prost::DecodeError),
+
+    #[error("index is required for random access")]
+    MissingIndex,
+
+    #[error("index {index} out of range for {len} records")]
+    IndexOutOfRange { index: usize, len: usize },
+
+    #[error("invalid index: {0}")]
+    InvalidIndex(String),
 }
 
 impl Error {
     pub fn from_raw_os_io_error(code: i32) -> Self {
         Self::IoError(std::io::Error::from_raw_os_error(code))
     }
+
+    /// Add where a read failed to I/O and checksum errors, other errors are unchanged.
+    pub fn with_context(self, context: ReadContext) -> Self {
+        match self {
+            Self::IoError(err) => {
+                Self::IoError(std::io::Error::new(err.kind(), format!("{context}: {err}")))
+            }
+            Self::ChecksumMismatch { found, expect, .. } => Self::ChecksumMismatch {
+                found,
+                expect,
+                context: Some(context),
+            },
+            err => err,
+        }
+    }
+}
+
+/// The file and offset of a failed read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReadContext {
+    /// `None` if the reader was given a `File` only.
+    pub path: Option<PathBuf>,
+    pub offset: u64,
+}
+
+impl ReadContext {
+    pub fn new(path: Option<PathBuf>, offset: u64) -> Self {
+        Self { path, offset }
+    }
+}
+
+impl fmt::Display for ReadContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            Some(path) => write!(f, "{} at offset {}", path.display(), self.offset),
+            None => write!(f, "read at offset {}", self.offset),
+        }
+    }
 }
@@ -0,0 +1,273 @@
+use std::{fs::File, path::Path};
+
+use crate::{
+    compression::RecordCompression,
+    constants::DEFAULT_MAX_RECORD_LENGTH,
+    error::{Error, Result},
+    indexing::{
+        cache::{open_or_build_index, IndexCache},
+        global_index::GlobalIndex,
+        source::IndexSource,
+        sync_reader::{IndexReader, MmapIndexReader},
+    },
+    record::read_record_at,
+};
+
+/// Map-style reader, every record is read with one `pread` through the index.
+///
+/// All methods take `&self`, so one reader can be shared between threads.
+/// The index can be any [`IndexSource`], by default the mmapped index file.
+pub struct IndexedTfrecordReader<I = MmapIndexReader> {
+    file: File,
+    index: I,
+    check_integrity: bool,
+    record_compression: bool,
+    max_record_length: u64,
+}
+
+impl IndexedTfrecordReader {
+    /// Default index path is `<file>.tfrecord.idx`, fails if the index is stale.
+    pub fn open<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        index_path: Option<Q>,
+        check_integrity: bool,
+    ) -> Result<Self> {
+        let index_path = index_path
+            .map(|p| p.as_ref().to_owned())
+            .unwrap_or_else(|| path.as_ref().with_extension("tfrecord.idx"));
+        let file = File::open(path)?;
+        let index = MmapIndexReader::open(index_path)?;
+        index.check_data(&file)?;
+        Ok(Self::new(file, index, check_integrity))
+    }
+}
+
+impl IndexedTfrecordReader<IndexReader> {
+    /// Like [`IndexedTfrecordReader::open`], but build a missing or stale index, see
+    /// [`open_or_build_index`].
+    pub fn open_or_build<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        index_path: Option<Q>,
+        cache: &IndexCache,
+        check_integrity: bool,
+    ) -> Result<Self> {
+        let index = open_or_build_index(
+            path.as_ref(),
+            index_path.as_ref().map(|p| p.as_ref()),
+            cache,
+        )?;
+        let file = File::open(path)?;
+        Ok(Self::new(file, index, check_integrity))
+    }
+}
+
+impl<I: IndexSource> IndexedTfrecordReader<I> {
+    pub fn new(file: File, index: I, check_integrity: bool) -> Self {
+        Self {
+            file,
+            index,
+            check_integrity,
+            record_compression: false,
+            max_record_length: DEFAULT_MAX_RECORD_LENGTH,
+        }
+    }
+
+    pub fn set_check_integrity(&mut self, check_integrity: bool) {
+        self.check_integrity = check_integrity;
+    }
+
+    /// Decompress records written with [`RecordCompression`].
+    pub fn set_record_compression(&mut self, record_compression: bool) {
+        self.record_compression = record_compression;
+    }
+
+    /// An index entry with a larger data length is treated as corrupted.
+    pub fn set_max_record_length(&mut self, max_record_length: u64) {
+        self.max_record_length = max_record_length;
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, index: usize) -> Result<Option<Vec<u8>>> {
+        match self.index.get(index) {
+            Some((offset, length)) => self.read_at(offset, length).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Read many records, reads are issued in file order and returned in the given order.
+    pub fn get_many(&self, indices: &[usize]) -> Result<Vec<Vec<u8>>> {
+        let mut order: Vec<usize> = (0..indices.len()).collect();
+        let mut entries = Vec::with_capacity(indices.len());
+        for &index in indices {
+            let entry = self.index.get(index).ok_or(Error::IndexOutOfRange {
+                index,
+                len: self.len(),
+            })?;
+            entries.push(entry);
+        }
+        order.sort_unstable_by_key(|&i| entries[i].0);
+
+        let mut records = vec![Vec::new(); indices.len()];
+        for i in order {
+            let (offset, length) = entries[i];
+            records[i] = self.read_at(offset, length)?;
+        }
+        Ok(records)
+    }
+
+    /// Read the whole record at `offset` and return the data.
+    pub fn read_at(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let buf = read_record_at(
+            &self.file,
+            offset,
+            length,
+            self.check_integrity,
+            self.max_record_length,
+        )?;
+        if self.record_compression {
+            return RecordCompression::decompress(&buf, self.max_record_length);
+        }
+        Ok(buf)
+    }
+}
+
+/// Map-style reader over all shards of a dataset, see [`GlobalIndex`].
+pub struct IndexedDatasetReader {
+    files: Vec<File>,
+    index: GlobalIndex,
+    check_integrity: bool,
+    record_compression: bool,
+    max_record_length: u64,
+}
+
+impl IndexedDatasetReader {
+    /// Open the manifest and every shard listed in it.
+    pub fn open<P: AsRef<Path>>(path: P, check_integrity: bool) -> Result<Self> {
+        let index = GlobalIndex::open(path)?;
+        Self::new(index, check_integrity)
+    }
+
+    pub fn new(index: GlobalIndex, check_integrity: bool) -> Result<Self> {
+        let files = index
+            .shard_paths()
+            .iter()
+            .map(File::open)
+            .collect::<std::io::Result<_>>()?;
+        Ok(Self {
+            files,
+            index,
+            check_integrity,
+            record_compression: false,
+            max_record_length: DEFAULT_MAX_RECORD_LENGTH,
+        })
+    }
+
+    pub fn set_check_integrity(&mut self, check_integrity: bool) {
+        self.check_integrity = check_integrity;
+    }
+
+    /// Decompress records written with [`RecordCompression`].
+    pub fn set_record_compression(&mut self, record_compression: bool) {
+        self.record_compression = record_compression;
+    }
+
+    /// An index entry with a larger data length is treated as corrupted.
+    pub fn set_max_record_length(&mut self, max_record_length: u64) {
+        self.max_record_length = max_record_length;
+    }
+
+    pub fn index(&self) -> &GlobalIndex {
+        &self.index
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.is_empty()
+    }
+
+    /// Read a record by its global id.
+    pub fn get(&self, global_id: usize) -> Result<Option<Vec<u8>>> {
+        let (shard, offset, length) = match self.index.get(global_id) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let buf = read_record_at(
+            &self.files[shard],
+            offset,
+            length,
+            self.check_integrity,
+            self.max_record_length,
+        )?;
+        if self.record_compression {
+            return RecordCompression::decompress(&buf, self.max_record_length).map(Some);
+        }
+        Ok(Some(buf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_writer::{OpenMode, TfrecordWriter};
+
+    #[test]
+    fn random_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let records: Vec<Vec<u8>> = (0..10).map(|i| vec![i as u8; 100 + i]).collect();
+
+        let mut writer =
+            TfrecordWriter::create_with_index(&path, None::<&Path>, OpenMode::Truncate).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = IndexedTfrecordReader::open(&path, None::<&Path>, true).unwrap();
+        assert_eq!(reader.len(), records.len());
+        assert_eq!(reader.get(7).unwrap().unwrap(), records[7]);
+        assert!(reader.get(records.len()).unwrap().is_none());
+
+        let indices = [9, 2, 5, 2, 0];
+        let expected: Vec<Vec<u8>> = indices.iter().map(|&i| records[i].clone()).collect();
+        assert_eq!(reader.get_many(&indices).unwrap(), expected);
+        assert!(matches!(
+            reader.get_many(&[1, 10]),
+            Err(Error::IndexOutOfRange { index: 10, len: 10 })
+        ));
+    }
+
+    #[test]
+    fn dataset_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut shards = Vec::new();
+        for shard in 0..3 {
+            let path = dir.path().join(format!("{shard}.tfrecord"));
+            let mut writer =
+                TfrecordWriter::create_with_index(&path, None::<&Path>, OpenMode::Truncate)
+                    .unwrap();
+            for i in 0..4 {
+                writer.write(&[shard as u8, i]).unwrap();
+            }
+            writer.finish().unwrap();
+            shards.push(path);
+        }
+        let path = dir.path().join("dataset.idx");
+        GlobalIndex::build(&path, &shards).unwrap();
+
+        let reader = IndexedDatasetReader::open(&path, true).unwrap();
+        assert_eq!(reader.len(), 12);
+        assert_eq!(reader.get(6).unwrap().unwrap(), vec![1, 2]);
+        assert!(reader.get(12).unwrap().is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    fs::{File, Metadata},
+    io::{BufReader, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::Result,
+    indexing::{format::DataInfo, sync_reader::IndexReader, sync_writer::SyncIndexWriter},
+    sync_reader::TfrecordReader,
+    sync_writer::temp_path,
+};
+
+/// Where an index built on open is kept, see [`open_or_build_index`].
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum IndexCache {
+    /// Only in memory, every open builds it again.
+    #[default]
+    Memory,
+    /// At the index path, `<file>.tfrecord.idx` by default.
+    NextToData,
+    /// In this directory, for datasets on read-only storage. The file name has a
+    /// hash of the absolute data path, so shards with the same name don't collide.
+    Dir(PathBuf),
+}
+
+impl IndexCache {
+    /// Where the index of `path` is persisted, `None` for [`IndexCache::Memory`].
+    pub fn cache_path(&self, path: &Path, index_path: &Path) -> Result<Option<PathBuf>> {
+        match self {
+            Self::Memory => Ok(None),
+            Self::NextToData => Ok(Some(index_path.to_owned())),
+            Self::Dir(dir) => {
+                let path = path.canonicalize()?;
+                let hash = crc32c::crc32c(path.to_string_lossy().as_bytes());
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                Ok(Some(dir.join(format!("{stem}.{hash:08x}.tfrecord.idx"))))
+            }
+        }
+    }
+}
+
+/// Open the index of `path`, default index path is `<file>.tfrecord.idx`.
+///
+/// The index path and then the cache are tried, an index that is missing, corrupted
+/// or stale is rebuilt from record headers and persisted according to `cache`.
+/// Persisting is best effort, the index is returned even if it can't be written.
+/// It goes through a temporary file and a rename, so processes opening the same
+/// shard at the same time never see a partial index.
+pub fn open_or_build_index<P: AsRef<Path>, Q: AsRef<Path>>(
+    path: P,
+    index_path: Option<Q>,
+    cache: &IndexCache,
+) -> Result<IndexReader> {
+    let path = path.as_ref();
+    let index_path = index_path
+        .map(|p| p.as_ref().to_owned())
+        .unwrap_or_else(|| path.with_extension("tfrecord.idx"));
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let cache_path = cache.cache_path(path, &index_path)?;
+
+    for candidate in std::iter::once(&index_path).chain(&cache_path) {
+        if let Ok(index) = open_fresh(candidate, &metadata) {
+            return Ok(index);
+        }
+    }
+
+    let buf = build_index(file, &metadata)?;
+    if let Some(cache_path) = cache_path {
+        let _ = persist(&cache_path, &buf);
+    }
+    IndexReader::new(&mut buf.as_slice())
+}
+
+fn open_fresh(index_path: &Path, metadata: &Metadata) -> Result<IndexReader> {
+    let index = IndexReader::open(index_path)?;
+    if let Some(footer) = index.footer() {
+        footer.check_data(metadata)?;
+    }
+    Ok(index)
+}
+
+/// Encoded index with footer, from record headers only.
+fn build_index(file: File, metadata: &Metadata) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut index_writer = SyncIndexWriter::new(&mut buf);
+    for entry in TfrecordReader::new(BufReader::new(file), true).indices() {
+        let (offset, length) = entry?;
+        index_writer.write_index(offset, length)?;
+    }
+    index_writer.write_footer(DataInfo::from_metadata(metadata))?;
+    Ok(buf)
+}
+
+/// Write to a temporary file, then rename.
+fn persist(path: &Path, buf: &[u8]) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let temp_path = temp_path(path);
+
+    let result = (|| {
+        let mut file = File::create(&temp_path)?;
+        file.write_all(buf)?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    Ok(result?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{indexing::source::IndexSource, sync_writer::TfrecordWriter};
+
+    fn write_data(path: &Path, num_records: usize) -> Vec<(u64, u64)> {
+        let mut writer = TfrecordWriter::create(path).unwrap();
+        for i in 0..num_records {
+            writer.write(&vec![i as u8; 10 + i]).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+        TfrecordReader::open(path, true)
+            .unwrap()
+            .indices()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn build_on_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let index_path = path.with_extension("tfrecord.idx");
+        let entries = write_data(&path, 5);
+
+        let index = open_or_build_index(&path, None::<&Path>, &IndexCache::Memory).unwrap();
+        assert_eq!(index.iter().collect::<Vec<_>>(), entries);
+        assert!(!index_path.exists());
+
+        let cache_dir = dir.path().join("cache");
+        let cache = IndexCache::Dir(cache_dir.clone());
+        open_or_build_index(&path, None::<&Path>, &cache).unwrap();
+        let cache_path = cache.cache_path(&path, &index_path).unwrap().unwrap();
+        assert!(cache_path.starts_with(&cache_dir));
+        assert_eq!(
+            IndexReader::open(&cache_path)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            entries
+        );
+        assert!(!index_path.exists());
+
+        open_or_build_index(&path, None::<&Path>, &IndexCache::NextToData).unwrap();
+        assert_eq!(
+            IndexReader::open(&index_path)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            entries
+        );
+
+        // the data changed, the stale cache is rebuilt and the other index is left alone
+        let entries = write_data(&path, 8);
+        let index = open_or_build_index(&path, None::<&Path>, &cache).unwrap();
+        assert_eq!(index.iter().collect::<Vec<_>>(), entries);
+        assert_eq!(IndexReader::open(&cache_path).unwrap().len(), 8);
+        assert_eq!(IndexReader::open(&index_path).unwrap().len(), 5);
+    }
+}
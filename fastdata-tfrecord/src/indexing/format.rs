@@ -0,0 +1,246 @@
+use std::{
+    fs::Metadata,
+    io::Read,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    constants::U64_SIZE,
+    error::{Error, Result},
+    record::{read_u32, read_u64},
+};
+
+pub const INDEX_MAGIC: [u8; 8] = *b"FDTFIDX\0";
+pub const INDEX_VERSION: u32 = 1;
+pub const ENTRY_SIZE: usize = U64_SIZE * 2;
+pub const FOOTER_SIZE: usize = 56;
+
+/// `data_crc` is the crc32c of the whole data file.
+const FLAG_DATA_CRC: u32 = 1;
+
+/// What the index knows about its data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DataInfo {
+    pub size: u64,
+    /// Nanoseconds since unix epoch, 0 if unknown
+    pub mtime: u64,
+    pub crc: Option<u32>,
+}
+
+impl DataInfo {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        let mtime = metadata.modified().ok().map_or(0, system_time_to_nanos);
+        Self {
+            size: metadata.len(),
+            mtime,
+            crc: None,
+        }
+    }
+}
+
+fn system_time_to_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+/// Footer written after the entries, so the index can be written in one pass.
+///
+/// ```text
+/// u32: version
+/// u32: flags
+/// u64: number of records
+/// u64: size of the data file
+/// u64: mtime of the data file in nanoseconds
+/// u32: crc32c of the data file, valid if flags & 1
+/// u32: crc32c of all entries
+/// u32: crc32c of the footer before this field
+/// u32: reserved
+/// [u8; 8]: magic
+/// ```
+///
+/// An index with a footer is always 8 bytes longer than a multiple of 16,
+/// a legacy index without footer is a multiple of 16.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexFooter {
+    pub version: u32,
+    pub num_records: u64,
+    pub data: DataInfo,
+    pub entries_crc: u32,
+}
+
+impl IndexFooter {
+    pub fn encode(&self) -> [u8; FOOTER_SIZE] {
+        let mut buf = [0; FOOTER_SIZE];
+        let flags = if self.data.crc.is_some() {
+            FLAG_DATA_CRC
+        } else {
+            0
+        };
+        buf[0..4].copy_from_slice(&self.version.to_le_bytes());
+        buf[4..8].copy_from_slice(&flags.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.num_records.to_le_bytes());
+        buf[16..24].copy_from_slice(&self.data.size.to_le_bytes());
+        buf[24..32].copy_from_slice(&self.data.mtime.to_le_bytes());
+        buf[32..36].copy_from_slice(&self.data.crc.unwrap_or(0).to_le_bytes());
+        buf[36..40].copy_from_slice(&self.entries_crc.to_le_bytes());
+        let footer_crc = crc32c::crc32c(&buf[..40]);
+        buf[40..44].copy_from_slice(&footer_crc.to_le_bytes());
+        buf[48..].copy_from_slice(&INDEX_MAGIC);
+        buf
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        if buf.len() != FOOTER_SIZE || buf[48..] != INDEX_MAGIC {
+            return Err(invalid("missing magic"));
+        }
+        if crc32c::crc32c(&buf[..40]) != read_u32(&buf[40..]) {
+            return Err(invalid("footer checksum mismatch"));
+        }
+
+        let version = read_u32(&buf[0..]);
+        if version != INDEX_VERSION {
+            return Err(invalid(&format!("unsupported version {version}")));
+        }
+
+        let flags = read_u32(&buf[4..]);
+        let data = DataInfo {
+            size: read_u64(&buf[16..]),
+            mtime: read_u64(&buf[24..]),
+            crc: (flags & FLAG_DATA_CRC != 0).then(|| read_u32(&buf[32..])),
+        };
+        Ok(Self {
+            version,
+            num_records: read_u64(&buf[8..]),
+            data,
+            entries_crc: read_u32(&buf[36..]),
+        })
+    }
+
+    /// Check the footer against the data file, the data crc is only checked by
+    /// [`IndexFooter::verify_data_crc`] because it reads the whole file.
+    pub fn check_data(&self, metadata: &Metadata) -> Result<()> {
+        let data = DataInfo::from_metadata(metadata);
+        if data.size != self.data.size {
+            return Err(Error::InvalidIndex(format!(
+                "stale index, data size is {} but index expects {}",
+                data.size, self.data.size
+            )));
+        }
+        if self.data.mtime != 0 && data.mtime != self.data.mtime {
+            return Err(Error::InvalidIndex(format!(
+                "stale index, data mtime is {} but index expects {}",
+                data.mtime, self.data.mtime
+            )));
+        }
+        Ok(())
+    }
+
+    /// Read the whole data file and compare its crc, do nothing if the index has no data crc.
+    pub fn verify_data_crc<R: Read>(&self, mut data: R) -> Result<()> {
+        let expect = match self.data.crc {
+            Some(crc) => crc,
+            None => return Ok(()),
+        };
+
+        let mut crc = 0;
+        let mut buf = vec![0; 1 << 20];
+        loop {
+            let n = data.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            crc = crc32c::crc32c_append(crc, &buf[..n]);
+        }
+
+        if crc != expect {
+            return Err(Error::InvalidIndex(format!(
+                "stale index, data crc is {crc:#010x} but index expects {expect:#010x}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Split an index file into entries and footer, `None` for a legacy index.
+pub fn split_index(buf: &[u8]) -> Result<(&[u8], Option<IndexFooter>)> {
+    match buf.len() % ENTRY_SIZE {
+        0 => Ok((buf, None)),
+        8 if buf.len() >= FOOTER_SIZE && buf.ends_with(&INDEX_MAGIC) => {
+            let (entries, footer) = buf.split_at(buf.len() - FOOTER_SIZE);
+            let footer = IndexFooter::decode(footer)?;
+
+            if entries.len() as u64 != footer.num_records * ENTRY_SIZE as u64 {
+                return Err(invalid(&format!(
+                    "{} records in footer, but {} bytes of entries",
+                    footer.num_records,
+                    entries.len()
+                )));
+            }
+            if crc32c::crc32c(entries) != footer.entries_crc {
+                return Err(invalid("entries checksum mismatch"));
+            }
+            Ok((entries, Some(footer)))
+        }
+        _ => Err(invalid(&format!(
+            "size {} is not a multiple of {ENTRY_SIZE}, truncated or misaligned",
+            buf.len()
+        ))),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::InvalidIndex(message.to_string())
+}
+
+#[inline]
+pub fn decode_entry(buf: &[u8]) -> (u64, u64) {
+    (read_u64(buf), read_u64(&buf[U64_SIZE..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn footer_round_trip() {
+        let footer = IndexFooter {
+            version: INDEX_VERSION,
+            num_records: 42,
+            data: DataInfo {
+                size: 1234,
+                mtime: 5678,
+                crc: Some(0xdeadbeef),
+            },
+            entries_crc: 0x12345678,
+        };
+        let buf = footer.encode();
+        assert_eq!(IndexFooter::decode(&buf).unwrap(), footer);
+
+        let mut corrupted = buf;
+        corrupted[10] ^= 1;
+        assert!(IndexFooter::decode(&corrupted).is_err());
+    }
+
+    #[test]
+    fn legacy_and_versioned() {
+        let entries: Vec<u8> = [(0u64, 20u64), (20, 30)]
+            .iter()
+            .flat_map(|(offset, length)| [offset.to_le_bytes(), length.to_le_bytes()].concat())
+            .collect();
+        assert!(split_index(&entries).unwrap().1.is_none());
+
+        let footer = IndexFooter {
+            version: INDEX_VERSION,
+            num_records: 2,
+            data: DataInfo::default(),
+            entries_crc: crc32c::crc32c(&entries),
+        };
+        let mut buf = entries.clone();
+        buf.extend_from_slice(&footer.encode());
+        assert_eq!(split_index(&buf).unwrap(), (&entries[..], Some(footer)));
+
+        buf[0] ^= 1;
+        assert!(split_index(&buf).is_err());
+        assert!(split_index(&entries[..20]).is_err());
+    }
+}
@@ -0,0 +1,428 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+
+use crate::{
+    constants::{U32_SIZE, U64_SIZE},
+    error::{Error, Result},
+    indexing::{
+        format::{decode_entry, ENTRY_SIZE},
+        source::IndexSource,
+        sync_reader::IndexReader,
+    },
+    record::{read_u32, read_u64},
+    sync_writer::temp_path,
+};
+
+pub const GLOBAL_INDEX_MAGIC: [u8; 8] = *b"FDTFGIX\0";
+pub const GLOBAL_INDEX_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 40;
+
+/// Dataset manifest, maps a global sample id to `(shard, offset, length)`.
+///
+/// ```text
+/// [u8; 8]: magic
+/// u32: version
+/// u32: reserved
+/// u64: number of shards
+/// u64: number of records
+/// u64: size of the shard names
+/// u64 * (num_shards + 1): prefix sums of records per shard, starting with 0
+/// (u64, u64) * num_records: offset and length, same as the per-file index
+/// (u32, [u8]) * num_shards: shard path relative to the manifest
+/// u32: crc32c of everything above
+/// ```
+///
+/// The shard of a sample is found by binary search over the prefix sums.
+pub struct GlobalIndex {
+    mmap: Mmap,
+    dir: PathBuf,
+    num_shards: usize,
+    len: usize,
+    shard_paths: Vec<PathBuf>,
+}
+
+impl GlobalIndex {
+    /// Build the manifest from the per-file indexes `<shard>.tfrecord.idx`.
+    ///
+    /// Shard paths are stored relative to the directory of the manifest when possible,
+    /// and the manifest is written to a temporary file and renamed into place.
+    pub fn build<P: AsRef<Path>, Q: AsRef<Path>>(path: P, shards: &[Q]) -> Result<()> {
+        let path = path.as_ref();
+        let dir = parent_dir(path);
+
+        let mut indexes = Vec::with_capacity(shards.len());
+        for shard in shards {
+            let shard = shard.as_ref();
+            let index = IndexReader::open(shard.with_extension("tfrecord.idx"))?;
+            index.check_data(&File::open(shard)?)?;
+            indexes.push(index);
+        }
+        let names = encode_shard_names(&dir, shards)?;
+        let num_records: usize = indexes.iter().map(|index| index.len()).sum();
+
+        let mut header = Vec::with_capacity(HEADER_SIZE);
+        header.extend_from_slice(&GLOBAL_INDEX_MAGIC);
+        header.extend_from_slice(&GLOBAL_INDEX_VERSION.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&(shards.len() as u64).to_le_bytes());
+        header.extend_from_slice(&(num_records as u64).to_le_bytes());
+        header.extend_from_slice(&(names.len() as u64).to_le_bytes());
+
+        write_checksummed(path, |writer| {
+            writer.write_all(&header)?;
+
+            let mut prefix_sum = 0u64;
+            writer.write_all(&prefix_sum.to_le_bytes())?;
+            for index in &indexes {
+                prefix_sum += index.len() as u64;
+                writer.write_all(&prefix_sum.to_le_bytes())?;
+            }
+            for index in &indexes {
+                for (offset, length) in index.iter() {
+                    writer.write_all(&offset.to_le_bytes())?;
+                    writer.write_all(&length.to_le_bytes())?;
+                }
+            }
+            writer.write_all(&names)?;
+            Ok(())
+        })
+    }
+
+    /// Map the manifest and validate its size, checksum and prefix sums.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let mmap = unsafe { Mmap::map(&file)? };
+        let invalid =
+            |message: String| Error::InvalidIndex(format!("{}: {message}", path.display()));
+
+        if mmap.len() < HEADER_SIZE + U64_SIZE + U32_SIZE || mmap[..8] != GLOBAL_INDEX_MAGIC {
+            return Err(invalid("not a global index".to_string()));
+        }
+        let version = read_u32(&mmap[8..]);
+        if version != GLOBAL_INDEX_VERSION {
+            return Err(invalid(format!("unsupported version {version}")));
+        }
+
+        let num_shards = read_u64(&mmap[16..]) as usize;
+        let len = read_u64(&mmap[24..]) as usize;
+        let names_size = read_u64(&mmap[32..]) as usize;
+        let expect_size = num_shards
+            .checked_add(1)
+            .and_then(|size| size.checked_mul(U64_SIZE))
+            .and_then(|size| size.checked_add(len.checked_mul(ENTRY_SIZE)?))
+            .and_then(|size| size.checked_add(HEADER_SIZE + names_size + U32_SIZE));
+        if expect_size != Some(mmap.len()) {
+            return Err(invalid(format!(
+                "size {} is truncated or misaligned",
+                mmap.len()
+            )));
+        }
+
+        let body_len = mmap.len() - U32_SIZE;
+        if !verify_checksummed(&mmap) {
+            return Err(invalid("checksum mismatch".to_string()));
+        }
+
+        let dir = parent_dir(path);
+        let mut index = Self {
+            mmap,
+            dir,
+            num_shards,
+            len,
+            shard_paths: Vec::new(),
+        };
+
+        let mut last = 0;
+        for shard in 0..=num_shards {
+            let prefix_sum = index.prefix_sum(shard);
+            if prefix_sum < last {
+                return Err(invalid(format!("prefix sums decrease at shard {shard}")));
+            }
+            last = prefix_sum;
+        }
+        if last != len as u64 {
+            return Err(invalid(format!(
+                "{last} records in shards, but {len} in header"
+            )));
+        }
+
+        let names = &index.mmap[index.names_offset()..body_len];
+        index.shard_paths = decode_shard_names(names, num_shards, &index.dir)
+            .ok_or_else(|| invalid("invalid shard name".to_string()))?;
+        Ok(index)
+    }
+
+    /// Total number of records in all shards.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn num_shards(&self) -> usize {
+        self.num_shards
+    }
+
+    pub fn shard_paths(&self) -> &[PathBuf] {
+        &self.shard_paths
+    }
+
+    /// Global ids of the records in `shard`, `None` if out of range.
+    pub fn shard_range(&self, shard: usize) -> Option<Range<usize>> {
+        if shard >= self.num_shards {
+            return None;
+        }
+        Some(self.prefix_sum(shard) as usize..self.prefix_sum(shard + 1) as usize)
+    }
+
+    /// Shard of a global id, `None` if out of range.
+    pub fn shard_of(&self, global_id: usize) -> Option<usize> {
+        if global_id >= self.len {
+            return None;
+        }
+        // first shard whose end is after global_id, empty shards are skipped
+        let (mut low, mut high) = (0, self.num_shards);
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.prefix_sum(mid + 1) as usize <= global_id {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        Some(low)
+    }
+
+    /// `(shard, offset, length)` of a global id.
+    pub fn get(&self, global_id: usize) -> Option<(usize, u64, u64)> {
+        let shard = self.shard_of(global_id)?;
+        let start = self.entries_offset() + global_id * ENTRY_SIZE;
+        let (offset, length) = decode_entry(&self.mmap[start..start + ENTRY_SIZE]);
+        Some((shard, offset, length))
+    }
+
+    /// The index of one shard, ids of the view are local to the shard.
+    pub fn shard_index(&self, shard: usize) -> Option<ShardIndex<'_>> {
+        Some(ShardIndex {
+            global_index: self,
+            range: self.shard_range(shard)?,
+        })
+    }
+
+    fn prefix_sum(&self, shard: usize) -> u64 {
+        read_u64(&self.mmap[HEADER_SIZE + shard * U64_SIZE..])
+    }
+
+    fn entries_offset(&self) -> usize {
+        HEADER_SIZE + (self.num_shards + 1) * U64_SIZE
+    }
+
+    fn names_offset(&self) -> usize {
+        self.entries_offset() + self.len * ENTRY_SIZE
+    }
+}
+
+/// See [`GlobalIndex::shard_index`].
+pub struct ShardIndex<'a> {
+    global_index: &'a GlobalIndex,
+    range: Range<usize>,
+}
+
+impl<'a> IndexSource for ShardIndex<'a> {
+    fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    fn get(&self, index: usize) -> Option<(u64, u64)> {
+        if index >= self.len() {
+            return None;
+        }
+        let (_, offset, length) = self.global_index.get(self.range.start + index)?;
+        Some((offset, length))
+    }
+}
+
+pub(crate) fn parent_dir(path: &Path) -> PathBuf {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// `(u32, [u8])` for every shard, paths are relative to `dir` when possible and absolute
+/// otherwise, so they don't depend on the working directory.
+pub(crate) fn encode_shard_names<Q: AsRef<Path>>(dir: &Path, shards: &[Q]) -> Result<Vec<u8>> {
+    let dir = std::path::absolute(dir)?;
+    let mut names = Vec::new();
+    for shard in shards {
+        let shard = std::path::absolute(shard)?;
+        let name = shard.strip_prefix(&dir).unwrap_or(&shard);
+        let name = name.to_str().ok_or_else(|| {
+            Error::InvalidIndex(format!("non utf-8 shard path {}", shard.display()))
+        })?;
+        names.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        names.extend_from_slice(name.as_bytes());
+    }
+    Ok(names)
+}
+
+pub(crate) fn decode_shard_names(
+    mut names: &[u8],
+    num_shards: usize,
+    dir: &Path,
+) -> Option<Vec<PathBuf>> {
+    let mut paths = Vec::with_capacity(num_shards);
+    for _ in 0..num_shards {
+        let name_len = read_u32(names.get(..U32_SIZE)?) as usize;
+        let name = names.get(U32_SIZE..U32_SIZE.checked_add(name_len)?)?;
+        paths.push(dir.join(std::str::from_utf8(name).ok()?));
+        names = &names[U32_SIZE + name_len..];
+    }
+    Some(paths)
+}
+
+/// Write a temporary file with a trailing crc32c of its content, then rename it into place.
+pub(crate) fn write_checksummed<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut CrcWriter<BufWriter<File>>) -> Result<()>,
+{
+    let temp_path = temp_path(path);
+    let mut writer = CrcWriter::new(BufWriter::new(File::create(&temp_path)?));
+    write(&mut writer)?;
+
+    let crc = writer.crc;
+    let mut writer = writer.writer;
+    writer.write_all(&crc.to_le_bytes())?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    std::fs::rename(&temp_path, path)?;
+    File::open(parent_dir(path))?.sync_all()?;
+    Ok(())
+}
+
+/// Check the trailing crc32c written by [`write_checksummed`].
+pub(crate) fn verify_checksummed(buf: &[u8]) -> bool {
+    match buf.len().checked_sub(U32_SIZE) {
+        Some(body_len) => crc32c::crc32c(&buf[..body_len]) == read_u32(&buf[body_len..]),
+        None => false,
+    }
+}
+
+pub(crate) struct CrcWriter<W> {
+    writer: W,
+    crc: u32,
+}
+
+impl<W> CrcWriter<W> {
+    fn new(writer: W) -> Self {
+        Self { writer, crc: 0 }
+    }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.writer.write(buf)?;
+        self.crc = crc32c::crc32c_append(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_writer::{OpenMode, TfrecordWriter};
+
+    #[test]
+    fn global_lookup() {
+        let dir = tempfile::tempdir().unwrap();
+        let counts = [3, 0, 5, 1];
+        let mut shards = Vec::new();
+        let mut expected = Vec::new();
+        for (shard, &count) in counts.iter().enumerate() {
+            let path = dir.path().join(format!("{shard}.tfrecord"));
+            let mut writer =
+                TfrecordWriter::create_with_index(&path, None::<&Path>, OpenMode::Truncate)
+                    .unwrap();
+            for i in 0..count {
+                let (offset, length) = writer.write(&vec![i as u8; 10 + i]).unwrap();
+                expected.push((shard, offset, length));
+            }
+            writer.finish().unwrap();
+            shards.push(path);
+        }
+
+        let path = dir.path().join("dataset.idx");
+        GlobalIndex::build(&path, &shards).unwrap();
+        let index = GlobalIndex::open(&path).unwrap();
+
+        assert_eq!(index.len(), 9);
+        assert_eq!(index.num_shards(), 4);
+        assert_eq!(index.shard_paths(), &shards[..]);
+        let entries: Vec<_> = (0..index.len()).map(|i| index.get(i).unwrap()).collect();
+        assert_eq!(entries, expected);
+        assert_eq!(index.get(9), None);
+        assert_eq!(index.shard_range(2), Some(3..8));
+        assert_eq!(index.shard_range(4), None);
+        assert_eq!(
+            index.shard_index(3).unwrap().iter().collect::<Vec<_>>(),
+            vec![(0, 26)]
+        );
+        assert!(index.shard_index(4).is_none());
+
+        let mut buf = std::fs::read(&path).unwrap();
+        buf[HEADER_SIZE] ^= 1;
+        std::fs::write(&path, &buf).unwrap();
+        assert!(matches!(
+            GlobalIndex::open(&path),
+            Err(Error::InvalidIndex(_))
+        ));
+
+        buf[16..24].copy_from_slice(&u64::MAX.to_le_bytes());
+        std::fs::write(&path, &buf).unwrap();
+        assert!(matches!(
+            GlobalIndex::open(&path),
+            Err(Error::InvalidIndex(_))
+        ));
+    }
+
+    #[test]
+    fn shards_in_sibling_dir() {
+        // relative paths, like `indexer manifest data/ -o out/dataset.idx`
+        let dir = tempfile::tempdir_in(".").unwrap();
+        let relative = dir
+            .path()
+            .strip_prefix(std::env::current_dir().unwrap())
+            .unwrap();
+        let data_dir = relative.join("data");
+        let out_dir = relative.join("out");
+        std::fs::create_dir(&data_dir).unwrap();
+        std::fs::create_dir(&out_dir).unwrap();
+
+        let shard = data_dir.join("0.tfrecord");
+        let mut writer =
+            TfrecordWriter::create_with_index(&shard, None::<&Path>, OpenMode::Truncate).unwrap();
+        writer.write(&[1; 10]).unwrap();
+        writer.finish().unwrap();
+
+        let path = out_dir.join("dataset.idx");
+        GlobalIndex::build(&path, &[&shard]).unwrap();
+        let index = GlobalIndex::open(&path).unwrap();
+        assert_eq!(index.shard_paths(), &[std::path::absolute(&shard).unwrap()]);
+        assert!(index.shard_paths()[0].is_file());
+    }
+}
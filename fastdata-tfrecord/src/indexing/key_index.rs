@@ -0,0 +1,491 @@
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::Write,
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use memmap2::Mmap;
+
+use crate::{
+    compression::RecordCompression,
+    constants::{DEFAULT_MAX_RECORD_LENGTH, U32_SIZE, U64_SIZE},
+    error::{Error, Result},
+    indexing::global_index::{
+        decode_shard_names, encode_shard_names, parent_dir, verify_checksummed, write_checksummed,
+    },
+    record::{read_record_at, read_u32, read_u64},
+    sync_reader::TfrecordReader,
+    tensorflow::Example,
+};
+
+pub const KEY_INDEX_MAGIC: [u8; 8] = *b"FDTFKIX\0";
+pub const KEY_INDEX_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 40;
+/// `(shard, offset, length)`
+const VALUE_SIZE: usize = U64_SIZE * 3;
+
+/// Type of the feature used as key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyKind {
+    Bytes = 0,
+    /// Stored big endian with the sign bit flipped, so keys sort like numbers.
+    Int64 = 1,
+}
+
+pub fn encode_int64_key(key: i64) -> [u8; 8] {
+    ((key as u64) ^ (1 << 63)).to_be_bytes()
+}
+
+/// Collect keys from shards and write a [`KeyIndex`].
+pub struct KeyIndexBuilder {
+    feature: String,
+    kind: KeyKind,
+    check_integrity: bool,
+    record_compression: bool,
+    shards: Vec<PathBuf>,
+    entries: Vec<(Vec<u8>, u64, u64, u64)>,
+}
+
+impl KeyIndexBuilder {
+    pub fn new(feature: &str, kind: KeyKind) -> Self {
+        Self {
+            feature: feature.to_string(),
+            kind,
+            check_integrity: true,
+            record_compression: false,
+            shards: Vec::new(),
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn set_check_integrity(&mut self, check_integrity: bool) {
+        self.check_integrity = check_integrity;
+    }
+
+    /// Decompress records written with [`RecordCompression`] before decoding, the index
+    /// must then be read with [`KeyIndex::set_record_compression`].
+    pub fn set_record_compression(&mut self, record_compression: bool) {
+        self.record_compression = record_compression;
+    }
+
+    /// Scan a shard and decode the feature of every `Example`.
+    ///
+    /// Every value of the feature becomes a key, a record without the feature is an error.
+    /// A shard that fails adds no keys, so the caller may skip it and go on.
+    pub fn add_shard<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let shard = self.shards.len() as u64;
+        let mut reader = TfrecordReader::open(path, self.check_integrity)?;
+        reader.set_record_compression(self.record_compression);
+
+        let mut entries = Vec::new();
+
+        loop {
+            let offset = reader.position()?;
+            let record = match reader.read()? {
+                Some(record) => record,
+                None => break,
+            };
+            let length = reader.position()? - offset;
+
+            let example = Example::from_bytes(&record)?;
+            let keys: Vec<Vec<u8>> = match self.kind {
+                KeyKind::Bytes => example
+                    .get_bytes_list(&self.feature)
+                    .map(|list| list.into_iter().map(|key| key.to_vec()).collect())
+                    .unwrap_or_default(),
+                KeyKind::Int64 => example
+                    .get_int64_list(&self.feature)
+                    .map(|list| {
+                        list.iter()
+                            .map(|&key| encode_int64_key(key).to_vec())
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            };
+            if keys.is_empty() {
+                return Err(Error::DataLoss(format!(
+                    "record at offset {offset} of {} has no {:?} feature {}",
+                    path.display(),
+                    self.kind,
+                    self.feature
+                )));
+            }
+            for key in keys {
+                entries.push((key, shard, offset, length));
+            }
+        }
+
+        self.entries.append(&mut entries);
+        self.shards.push(path.to_owned());
+        Ok(())
+    }
+
+    /// Sort the keys and write the index, shard paths are stored relative to it.
+    pub fn write<P: AsRef<Path>>(mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        self.entries.sort_unstable();
+        let names = encode_shard_names(&parent_dir(path), &self.shards)?;
+        let keys_size: usize = self.entries.iter().map(|entry| entry.0.len()).sum();
+
+        write_checksummed(path, |writer| {
+            writer.write_all(&KEY_INDEX_MAGIC)?;
+            writer.write_all(&KEY_INDEX_VERSION.to_le_bytes())?;
+            writer.write_all(&(self.kind as u32).to_le_bytes())?;
+            writer.write_all(&(self.shards.len() as u64).to_le_bytes())?;
+            writer.write_all(&(self.entries.len() as u64).to_le_bytes())?;
+            writer.write_all(&(keys_size as u64).to_le_bytes())?;
+
+            let mut key_offset = 0u64;
+            writer.write_all(&key_offset.to_le_bytes())?;
+            for (key, ..) in &self.entries {
+                key_offset += key.len() as u64;
+                writer.write_all(&key_offset.to_le_bytes())?;
+            }
+            for (_, shard, offset, length) in &self.entries {
+                writer.write_all(&shard.to_le_bytes())?;
+                writer.write_all(&offset.to_le_bytes())?;
+                writer.write_all(&length.to_le_bytes())?;
+            }
+            for (key, ..) in &self.entries {
+                writer.write_all(key)?;
+            }
+            writer.write_all(&names)?;
+            Ok(())
+        })
+    }
+}
+
+/// Sorted `key -> (shard, offset, length)` table, looked up by binary search through an mmap.
+///
+/// ```text
+/// [u8; 8]: magic
+/// u32: version
+/// u32: key kind, 0 for bytes and 1 for int64
+/// u64: number of shards
+/// u64: number of keys
+/// u64: size of all keys
+/// u64 * (num_keys + 1): end of each key in the key blob, starting with 0
+/// (u64, u64, u64) * num_keys: shard, offset and length of the record
+/// [u8]: sorted keys
+/// (u32, [u8]) * num_shards: shard path relative to the index
+/// u32: crc32c of everything above
+/// ```
+pub struct KeyIndex {
+    mmap: Mmap,
+    kind: KeyKind,
+    len: usize,
+    shard_paths: Vec<PathBuf>,
+    record_compression: bool,
+    max_record_length: u64,
+}
+
+impl KeyIndex {
+    /// Map the index and validate its size and checksum.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mmap = unsafe { Mmap::map(&File::open(path)?)? };
+        let invalid = |message: &str| Error::InvalidIndex(format!("{}: {message}", path.display()));
+
+        if mmap.len() < HEADER_SIZE + U64_SIZE + U32_SIZE || mmap[..8] != KEY_INDEX_MAGIC {
+            return Err(invalid("not a key index"));
+        }
+        if read_u32(&mmap[8..]) != KEY_INDEX_VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        let kind = match read_u32(&mmap[12..]) {
+            0 => KeyKind::Bytes,
+            1 => KeyKind::Int64,
+            _ => return Err(invalid("unknown key kind")),
+        };
+        let num_shards = read_u64(&mmap[16..]) as usize;
+        let len = read_u64(&mmap[24..]) as usize;
+        let keys_size = read_u64(&mmap[32..]) as usize;
+
+        let names_offset = len
+            .checked_add(1)
+            .and_then(|n| n.checked_mul(U64_SIZE))
+            .and_then(|size| size.checked_add(len.checked_mul(VALUE_SIZE)?))
+            .and_then(|size| size.checked_add(HEADER_SIZE.checked_add(keys_size)?))
+            .filter(|&size| size + U32_SIZE <= mmap.len())
+            .ok_or_else(|| invalid("truncated index"))?;
+        if !verify_checksummed(&mmap) {
+            return Err(invalid("checksum mismatch"));
+        }
+
+        let names = &mmap[names_offset..mmap.len() - U32_SIZE];
+        let shard_paths = decode_shard_names(names, num_shards, &parent_dir(path))
+            .ok_or_else(|| invalid("invalid shard name"))?;
+
+        let index = Self {
+            mmap,
+            kind,
+            len,
+            shard_paths,
+            record_compression: false,
+            max_record_length: DEFAULT_MAX_RECORD_LENGTH,
+        };
+        let mut last = 0;
+        for i in 0..=len {
+            let key_end = index.key_end(i);
+            if key_end < last {
+                return Err(invalid("key offsets decrease"));
+            }
+            last = key_end;
+        }
+        if last != keys_size as u64 {
+            return Err(invalid("key offsets don't match the size of keys"));
+        }
+        for i in 0..len {
+            if index.value(i).0 >= num_shards {
+                return Err(invalid("shard out of range"));
+            }
+        }
+        Ok(index)
+    }
+
+    /// Number of keys.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn kind(&self) -> KeyKind {
+        self.kind
+    }
+
+    /// Decompress records written with [`RecordCompression`] in [`KeyIndex::read`],
+    /// set it if the index was built with it.
+    pub fn set_record_compression(&mut self, record_compression: bool) {
+        self.record_compression = record_compression;
+    }
+
+    /// A record with a larger data length is treated as corrupted.
+    pub fn set_max_record_length(&mut self, max_record_length: u64) {
+        self.max_record_length = max_record_length;
+    }
+
+    pub fn shard_paths(&self) -> &[PathBuf] {
+        &self.shard_paths
+    }
+
+    /// `(shard, offset, length)` of the first record with `key`.
+    pub fn get(&self, key: &[u8]) -> Option<(usize, u64, u64)> {
+        let range = self.equal_range(key);
+        (!range.is_empty()).then(|| self.value(range.start))
+    }
+
+    /// All records with `key`, sorted by shard and offset.
+    pub fn get_all(&self, key: &[u8]) -> Vec<(usize, u64, u64)> {
+        self.equal_range(key).map(|i| self.value(i)).collect()
+    }
+
+    pub fn get_int64(&self, key: i64) -> Option<(usize, u64, u64)> {
+        self.get(&encode_int64_key(key))
+    }
+
+    /// Read the data of the first record with `key`.
+    pub fn read(&self, key: &[u8], check_integrity: bool) -> Result<Option<Vec<u8>>> {
+        let (shard, offset, length) = match self.get(key) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let file = File::open(&self.shard_paths[shard])?;
+        let buf = read_record_at(
+            &file,
+            offset,
+            length,
+            check_integrity,
+            self.max_record_length,
+        )?;
+        if self.record_compression {
+            return RecordCompression::decompress(&buf, self.max_record_length).map(Some);
+        }
+        Ok(Some(buf))
+    }
+
+    fn equal_range(&self, key: &[u8]) -> Range<usize> {
+        let start = self.partition_point(|k| k.cmp(key) == Ordering::Less);
+        let end = self.partition_point(|k| k.cmp(key) != Ordering::Greater);
+        start..end
+    }
+
+    /// First `i` for which `pred(key(i))` is false, keys are sorted.
+    fn partition_point<F: Fn(&[u8]) -> bool>(&self, pred: F) -> usize {
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let mid = (low + high) / 2;
+            if pred(self.key(mid)) {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    }
+
+    fn key_end(&self, i: usize) -> u64 {
+        read_u64(&self.mmap[HEADER_SIZE + i * U64_SIZE..])
+    }
+
+    fn key(&self, i: usize) -> &[u8] {
+        let start = self.keys_offset() + self.key_end(i) as usize;
+        let end = self.keys_offset() + self.key_end(i + 1) as usize;
+        &self.mmap[start..end]
+    }
+
+    fn value(&self, i: usize) -> (usize, u64, u64) {
+        let start = self.values_offset() + i * VALUE_SIZE;
+        (
+            read_u64(&self.mmap[start..]) as usize,
+            read_u64(&self.mmap[start + U64_SIZE..]),
+            read_u64(&self.mmap[start + U64_SIZE * 2..]),
+        )
+    }
+
+    fn values_offset(&self) -> usize {
+        HEADER_SIZE + (self.len + 1) * U64_SIZE
+    }
+
+    fn keys_offset(&self) -> usize {
+        self.values_offset() + self.len * VALUE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use prost::Message;
+
+    use super::*;
+    use crate::{
+        sync_writer::TfrecordWriter,
+        tensorflow::{Example, Feature},
+    };
+
+    /// Two shards of 5 examples with `fname` `img_<id>.jpg` and `id` `id - 3`.
+    fn write_shards(dir: &Path) -> Vec<PathBuf> {
+        let mut shards = Vec::new();
+        for shard in 0..2 {
+            let path = dir.join(format!("{shard}.tfrecord"));
+            let mut writer = TfrecordWriter::create(&path).unwrap();
+            for i in 0..5i64 {
+                let id = shard * 5 + i;
+                let example = Example::from([
+                    (
+                        "fname",
+                        Feature::from(vec![format!("img_{id}.jpg").into_bytes()]),
+                    ),
+                    ("id", Feature::from(vec![id - 3])),
+                ]);
+                writer.write(&example.encode_to_vec()).unwrap();
+            }
+            writer.finish().unwrap();
+            shards.push(path);
+        }
+        shards
+    }
+
+    #[test]
+    fn bytes_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let shards = write_shards(dir.path());
+
+        let path = dir.path().join("fname.kidx");
+        let mut builder = KeyIndexBuilder::new("fname", KeyKind::Bytes);
+        for shard in &shards {
+            builder.add_shard(shard).unwrap();
+        }
+        builder.write(&path).unwrap();
+
+        let index = KeyIndex::open(&path).unwrap();
+        assert_eq!(index.len(), 10);
+        assert_eq!(index.shard_paths(), &shards[..]);
+        let record = index.read(b"img_7.jpg", true).unwrap().unwrap();
+        let example = Example::from_bytes(&record).unwrap();
+        assert_eq!(example.get_int64_list("id").unwrap(), &[4]);
+        assert_eq!(index.get(b"img_10.jpg"), None);
+    }
+
+    #[test]
+    fn int64_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let shards = write_shards(dir.path());
+
+        let path = dir.path().join("id.kidx");
+        let mut builder = KeyIndexBuilder::new("id", KeyKind::Int64);
+        builder.add_shard(&shards[1]).unwrap();
+        builder.add_shard(&shards[0]).unwrap();
+        builder.write(&path).unwrap();
+
+        let index = KeyIndex::open(&path).unwrap();
+        assert_eq!(index.get_int64(-3).unwrap().0, 1);
+        assert_eq!(index.get_int64(6).unwrap().0, 0);
+        assert_eq!(index.get_int64(7), None);
+        let keys: Vec<&[u8]> = (0..index.len()).map(|i| index.key(i)).collect();
+        assert!(keys.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn missing_feature() {
+        let dir = tempfile::tempdir().unwrap();
+        let shards = write_shards(dir.path());
+
+        let mut builder = KeyIndexBuilder::new("label", KeyKind::Int64);
+        assert!(matches!(
+            builder.add_shard(&shards[0]),
+            Err(Error::DataLoss(_))
+        ));
+
+        // the keys read before the bad record are dropped with its shard
+        let bad = dir.path().join("bad.tfrecord");
+        let mut writer = TfrecordWriter::create(&bad).unwrap();
+        for example in [
+            Example::from([("id", Feature::from(vec![100i64]))]),
+            Example::from([("other", Feature::from(vec![0i64]))]),
+        ] {
+            writer.write(&example.encode_to_vec()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let path = dir.path().join("id.kidx");
+        let mut builder = KeyIndexBuilder::new("id", KeyKind::Int64);
+        assert!(builder.add_shard(&bad).is_err());
+        builder.add_shard(&shards[0]).unwrap();
+        builder.write(&path).unwrap();
+
+        let index = KeyIndex::open(&path).unwrap();
+        assert_eq!(index.len(), 5);
+        assert_eq!(index.shard_paths(), &shards[..1]);
+        assert_eq!(index.get_int64(100), None);
+    }
+
+    #[test]
+    fn compressed_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let compressed = dir.path().join("compressed.tfrecord");
+        let mut writer = TfrecordWriter::create(&compressed).unwrap();
+        writer.set_record_compression(Some(RecordCompression::Zstd));
+        let example = Example::from([
+            ("id", Feature::from(vec![42i64])),
+            ("pad", Feature::from(vec![vec![0u8; 1000]])),
+        ]);
+        writer.write(&example.encode_to_vec()).unwrap();
+        writer.finish().unwrap();
+
+        let path = dir.path().join("compressed.kidx");
+        let mut builder = KeyIndexBuilder::new("id", KeyKind::Int64);
+        builder.set_record_compression(true);
+        builder.add_shard(&compressed).unwrap();
+        builder.write(&path).unwrap();
+
+        let mut index = KeyIndex::open(&path).unwrap();
+        index.set_record_compression(true);
+        let record = index.read(&encode_int64_key(42), true).unwrap().unwrap();
+        assert_eq!(record, example.encode_to_vec());
+    }
+}
@@ -1,2 +1,8 @@
+pub mod cache;
+pub mod format;
+pub mod global_index;
+pub mod key_index;
+pub mod source;
 pub mod sync_reader;
 pub mod sync_writer;
+pub mod text;
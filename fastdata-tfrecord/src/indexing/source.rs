@@ -0,0 +1,142 @@
+use std::ops::Range;
+
+use crate::error::{Error, Result};
+
+/// Random access to `(offset, length)` index entries.
+///
+/// Implemented by [`IndexReader`](super::sync_reader::IndexReader) (file read into memory),
+/// [`MmapIndexReader`](super::sync_reader::MmapIndexReader) and `Vec<(u64, u64)>`.
+/// Backends validate their input when they are created, so `get` never panics.
+pub trait IndexSource {
+    fn len(&self) -> usize;
+
+    /// `None` if `index` is out of range.
+    fn get(&self, index: usize) -> Option<(u64, u64)>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn iter(&self) -> Iter<'_, Self>
+    where
+        Self: Sized,
+    {
+        Iter {
+            source: self,
+            range: 0..self.len(),
+        }
+    }
+
+    /// A view of `range`, indices of the view start from 0.
+    fn slice(&self, range: Range<usize>) -> Result<Slice<'_, Self>>
+    where
+        Self: Sized,
+    {
+        let len = self.len();
+        if range.start > range.end || range.end > len {
+            return Err(Error::IndexOutOfRange {
+                index: range.end,
+                len,
+            });
+        }
+        Ok(Slice {
+            source: self,
+            range,
+        })
+    }
+}
+
+impl IndexSource for Vec<(u64, u64)> {
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn get(&self, index: usize) -> Option<(u64, u64)> {
+        self.as_slice().get(index).copied()
+    }
+}
+
+impl<S: IndexSource + ?Sized> IndexSource for &S {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn get(&self, index: usize) -> Option<(u64, u64)> {
+        (**self).get(index)
+    }
+}
+
+impl<S: IndexSource + ?Sized> IndexSource for Box<S> {
+    fn len(&self) -> usize {
+        (**self).len()
+    }
+
+    fn get(&self, index: usize) -> Option<(u64, u64)> {
+        (**self).get(index)
+    }
+}
+
+pub struct Iter<'a, S> {
+    source: &'a S,
+    range: Range<usize>,
+}
+
+impl<'a, S: IndexSource> Iterator for Iter<'a, S> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().and_then(|index| self.source.get(index))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+
+impl<'a, S: IndexSource> ExactSizeIterator for Iter<'a, S> {}
+
+/// Owning iterator, see [`IndexSource::iter`].
+pub struct IntoIter<S> {
+    source: S,
+    range: Range<usize>,
+}
+
+impl<S: IndexSource> IntoIter<S> {
+    pub fn new(source: S) -> Self {
+        let range = 0..source.len();
+        Self { source, range }
+    }
+}
+
+impl<S: IndexSource> Iterator for IntoIter<S> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.range.next().and_then(|index| self.source.get(index))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.range.size_hint()
+    }
+}
+
+impl<S: IndexSource> ExactSizeIterator for IntoIter<S> {}
+
+pub struct Slice<'a, S> {
+    source: &'a S,
+    range: Range<usize>,
+}
+
+impl<'a, S: IndexSource> IndexSource for Slice<'a, S> {
+    fn len(&self) -> usize {
+        self.range.len()
+    }
+
+    fn get(&self, index: usize) -> Option<(u64, u64)> {
+        if index < self.len() {
+            self.source.get(self.range.start + index)
+        } else {
+            None
+        }
+    }
+}
@@ -1,10 +1,18 @@
 use std::{fs::File, io::Read, path::Path};
 
-use crate::{constants::U64_SIZE, error::Result};
+use crate::{
+    error::Result,
+    indexing::{
+        format::{decode_entry, split_index, IndexFooter, ENTRY_SIZE},
+        source::{IndexSource, IntoIter},
+    },
+};
 use memmap2::Mmap;
 
 pub struct MmapIndexReader {
     mmap: Mmap,
+    len: usize,
+    footer: Option<IndexFooter>,
 }
 
 impl MmapIndexReader {
@@ -13,76 +21,48 @@ impl MmapIndexReader {
         Self::new(file)
     }
 
+    /// Fail if the index is truncated, misaligned or its footer doesn't match the entries.
     pub fn new(file: File) -> Result<Self> {
         let mmap = unsafe { Mmap::map(&file)? };
-        Ok(Self { mmap })
+        let (entries, footer) = split_index(&mmap)?;
+        let len = entries.len() / ENTRY_SIZE;
+        Ok(Self { mmap, len, footer })
     }
 
-    pub fn read_index(&self, index: usize) -> (u64, u64) {
-        let bytes_index = index * U64_SIZE * 2;
-
-        let buf = &self.mmap[bytes_index..bytes_index + U64_SIZE * 2];
-
-        let offset = u64::from_le_bytes(buf[..U64_SIZE].try_into().unwrap());
-        let length = u64::from_le_bytes(buf[U64_SIZE..].try_into().unwrap());
-        (offset, length)
-    }
-
-    pub fn get(&self, index: usize) -> Option<(u64, u64)> {
-        if index < self.len() {
-            Some(self.read_index(index))
-        } else {
-            None
-        }
+    /// `None` for a legacy index written without footer.
+    pub fn footer(&self) -> Option<&IndexFooter> {
+        self.footer.as_ref()
     }
 
-    pub fn len(&self) -> usize {
-        self.mmap.len() / (U64_SIZE * 2)
+    /// Fail if the data file changed since the index was written, legacy index always passes.
+    pub fn check_data(&self, data_file: &File) -> Result<()> {
+        check_data(self.footer.as_ref(), data_file)
     }
+}
 
-    pub fn iter(&self) -> Iter<'_> {
-        Iter {
-            reader: self,
-            index: 0,
-        }
+impl IndexSource for MmapIndexReader {
+    fn len(&self) -> usize {
+        self.len
     }
 
-    pub fn into_iter(self) -> IntoIter {
-        IntoIter {
-            reader: self,
-            index: 0,
-        }
+    fn get(&self, index: usize) -> Option<(u64, u64)> {
+        get_entry(&self.mmap[..self.len * ENTRY_SIZE], index)
     }
 }
 
-pub struct Iter<'a> {
-    reader: &'a MmapIndexReader,
-    index: usize,
-}
-
-impl<'a> Iterator for Iter<'a> {
+impl IntoIterator for MmapIndexReader {
     type Item = (u64, u64);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.index += 1;
-        self.reader.get(self.index - 1)
-    }
-}
+    type IntoIter = IntoIter<Self>;
 
-pub struct IntoIter {
-    reader: MmapIndexReader,
-    index: usize,
-}
-
-impl Iterator for IntoIter {
-    type Item = (u64, u64);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.index += 1;
-        self.reader.get(self.index - 1)
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter::new(self)
     }
 }
 
+/// Read the whole index file into memory.
 pub struct IndexReader {
     buf: Vec<u8>,
+    footer: Option<IndexFooter>,
 }
 
 impl IndexReader {
@@ -91,71 +71,93 @@ impl IndexReader {
         Self::new(&mut file)
     }
 
-    pub fn new(file: &mut File) -> Result<Self> {
+    /// Fail if the index is truncated, misaligned or its footer doesn't match the entries.
+    pub fn new<R: Read>(reader: &mut R) -> Result<Self> {
         let mut buf = Vec::new();
-        file.read_to_end(&mut buf);
-        Ok(Self { buf })
+        reader.read_to_end(&mut buf)?;
+        let (entries, footer) = split_index(&buf)?;
+        buf.truncate(entries.len());
+        Ok(Self { buf, footer })
     }
 
-    pub fn read_index(&self, index: usize) -> (u64, u64) {
-        let bytes_index = index * U64_SIZE * 2;
-
-        let buf = &self.buf[bytes_index..bytes_index + U64_SIZE * 2];
-
-        let offset = u64::from_le_bytes(buf[..U64_SIZE].try_into().unwrap());
-        let length = u64::from_le_bytes(buf[U64_SIZE..].try_into().unwrap());
-        (offset, length)
+    /// `None` for a legacy index written without footer.
+    pub fn footer(&self) -> Option<&IndexFooter> {
+        self.footer.as_ref()
     }
 
-    pub fn get(&self, index: usize) -> Option<(u64, u64)> {
-        if index < self.len() {
-            Some(self.read_index(index))
-        } else {
-            None
-        }
+    /// Fail if the data file changed since the index was written, legacy index always passes.
+    pub fn check_data(&self, data_file: &File) -> Result<()> {
+        check_data(self.footer.as_ref(), data_file)
     }
+}
 
-    pub fn len(&self) -> usize {
-        self.buf.len() / (U64_SIZE * 2)
+impl IndexSource for IndexReader {
+    fn len(&self) -> usize {
+        self.buf.len() / ENTRY_SIZE
     }
 
-    pub fn iter(&self) -> Iter2<'_> {
-        Iter2 {
-            reader: self,
-            index: 0,
-        }
+    fn get(&self, index: usize) -> Option<(u64, u64)> {
+        get_entry(&self.buf, index)
     }
+}
 
-    pub fn into_iter(self) -> IntoIter2 {
-        IntoIter2 {
-            reader: self,
-            index: 0,
-        }
+impl IntoIterator for IndexReader {
+    type Item = (u64, u64);
+    type IntoIter = IntoIter<Self>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter::new(self)
     }
 }
 
-pub struct Iter2<'a> {
-    reader: &'a IndexReader,
-    index: usize,
+fn get_entry(entries: &[u8], index: usize) -> Option<(u64, u64)> {
+    let start = index.checked_mul(ENTRY_SIZE)?;
+    entries.get(start..start + ENTRY_SIZE).map(decode_entry)
 }
 
-impl<'a> Iterator for Iter2<'a> {
-    type Item = (u64, u64);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.index += 1;
-        self.reader.get(self.index - 1)
+fn check_data(footer: Option<&IndexFooter>, data_file: &File) -> Result<()> {
+    match footer {
+        Some(footer) => footer.check_data(&data_file.metadata()?),
+        None => Ok(()),
     }
 }
 
-pub struct IntoIter2 {
-    reader: IndexReader,
-    index: usize,
-}
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
 
-impl Iterator for IntoIter2 {
-    type Item = (u64, u64);
-    fn next(&mut self) -> Option<Self::Item> {
-        self.index += 1;
-        self.reader.get(self.index - 1)
+    use super::*;
+    use crate::error::Error;
+
+    #[test]
+    fn sources_agree() {
+        let entries: Vec<(u64, u64)> = (0..10).map(|i| (i * 30, 30)).collect();
+        let mut buf = Vec::new();
+        for (offset, length) in &entries {
+            buf.extend_from_slice(&offset.to_le_bytes());
+            buf.extend_from_slice(&length.to_le_bytes());
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord.idx");
+        std::fs::write(&path, &buf).unwrap();
+
+        let file_source = IndexReader::open(&path).unwrap();
+        let mmap_source = MmapIndexReader::open(&path).unwrap();
+        assert_eq!(file_source.iter().collect::<Vec<_>>(), entries);
+        assert_eq!(mmap_source.into_iter().collect::<Vec<_>>(), entries);
+        assert_eq!(entries.get(10), None);
+
+        let slice = entries.slice(2..5).unwrap();
+        assert_eq!(slice.iter().collect::<Vec<_>>(), entries[2..5]);
+        assert_eq!(slice.get(3), None);
+        assert!(entries.slice(5..11).is_err());
+
+        for len in [buf.len() - 1, buf.len() - 8, 3] {
+            assert!(matches!(
+                IndexReader::new(&mut Cursor::new(&buf[..len])),
+                Err(Error::InvalidIndex(_))
+            ));
+        }
     }
 }
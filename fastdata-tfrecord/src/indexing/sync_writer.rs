@@ -1,8 +1,13 @@
-use crate::error::Result;
+use crate::{
+    error::Result,
+    indexing::format::{DataInfo, IndexFooter, INDEX_VERSION},
+};
 use std::io::Write;
 
 pub struct SyncIndexWriter<T> {
     writer: T,
+    num_records: u64,
+    entries_crc: u32,
 }
 
 impl<T> SyncIndexWriter<T>
@@ -10,12 +15,38 @@ where
     T: Write,
 {
     pub fn new(writer: T) -> Self {
-        Self { writer }
+        Self::with_state(writer, 0, 0)
+    }
+
+    /// Continue after `num_records` existing entries whose crc32c is `entries_crc`.
+    pub fn with_state(writer: T, num_records: u64, entries_crc: u32) -> Self {
+        Self {
+            writer,
+            num_records,
+            entries_crc,
+        }
     }
 
     pub fn write_index(&mut self, offset: u64, length: u64) -> Result<()> {
-        self.writer.write_all(&offset.to_le_bytes())?;
-        self.writer.write_all(&length.to_le_bytes())?;
+        let offset_buf = offset.to_le_bytes();
+        let length_buf = length.to_le_bytes();
+        self.writer.write_all(&offset_buf)?;
+        self.writer.write_all(&length_buf)?;
+        self.entries_crc = crc32c::crc32c_append(self.entries_crc, &offset_buf);
+        self.entries_crc = crc32c::crc32c_append(self.entries_crc, &length_buf);
+        self.num_records += 1;
+        Ok(())
+    }
+
+    /// Seal the index, see [`IndexFooter`]. No entry can be written after the footer.
+    pub fn write_footer(&mut self, data: DataInfo) -> Result<()> {
+        let footer = IndexFooter {
+            version: INDEX_VERSION,
+            num_records: self.num_records,
+            data,
+            entries_crc: self.entries_crc,
+        };
+        self.writer.write_all(&footer.encode())?;
         Ok(())
     }
 
@@ -0,0 +1,108 @@
+use std::{
+    fs::File,
+    io::{BufRead, BufReader, Read, Write},
+    path::Path,
+};
+
+use crate::{
+    error::{Error, Result},
+    indexing::{format::INDEX_MAGIC, source::IndexSource},
+};
+
+/// On-disk layout of an index file.
+///
+/// NVIDIA DALI (`tfrecord2idx`) and the python `tfrecord` package write one
+/// `"<offset> <length>\n"` line per record, `length` is the size of the whole record
+/// like in the binary index, so entries can be converted without the data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexFormat {
+    /// `.tfrecord.idx` written by this crate.
+    #[default]
+    Binary,
+    /// Text index of DALI and python `tfrecord`.
+    Text,
+}
+
+impl IndexFormat {
+    /// A text index only contains digits and whitespace, a binary index with footer ends
+    /// with the magic and a legacy one has zero bytes in every entry.
+    pub fn detect(buf: &[u8]) -> Self {
+        if !buf.is_empty()
+            && !buf.ends_with(&INDEX_MAGIC)
+            && buf
+                .iter()
+                .all(|b| b.is_ascii_digit() || b.is_ascii_whitespace())
+        {
+            Self::Text
+        } else {
+            Self::Binary
+        }
+    }
+
+    pub fn detect_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut buf = Vec::new();
+        File::open(path)?.take(4096).read_to_end(&mut buf)?;
+        Ok(Self::detect(&buf))
+    }
+}
+
+/// Parse `"<offset> <length>"` lines, empty lines are skipped.
+pub fn read_text_index<R: BufRead>(reader: R) -> Result<Vec<(u64, u64)>> {
+    let mut entries = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        let mut fields = line.split_whitespace();
+        let entry = match (fields.next(), fields.next(), fields.next()) {
+            (None, ..) => continue,
+            (Some(offset), Some(length), None) => offset.parse().ok().zip(length.parse().ok()),
+            _ => None,
+        };
+        let entry = entry.ok_or_else(|| {
+            Error::InvalidIndex(format!(
+                "line {}: expect \"<offset> <length>\", found {line:?}",
+                i + 1
+            ))
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+pub fn open_text_index<P: AsRef<Path>>(path: P) -> Result<Vec<(u64, u64)>> {
+    read_text_index(BufReader::new(File::open(path)?))
+}
+
+pub fn write_text_index<W: Write, I: IndexSource>(mut writer: W, index: &I) -> Result<()> {
+    for (offset, length) in index.iter() {
+        writeln!(writer, "{offset} {length}")?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn text_round_trip() {
+        let entries: Vec<(u64, u64)> = vec![(0, 120), (120, 37), (157, 1000)];
+        let mut buf = Vec::new();
+        write_text_index(&mut buf, &entries).unwrap();
+        assert_eq!(buf, b"0 120\n120 37\n157 1000\n");
+        assert_eq!(IndexFormat::detect(&buf), IndexFormat::Text);
+        assert_eq!(read_text_index(Cursor::new(&buf)).unwrap(), entries);
+
+        let mut binary = Vec::new();
+        for (offset, length) in &entries {
+            binary.extend_from_slice(&offset.to_le_bytes());
+            binary.extend_from_slice(&length.to_le_bytes());
+        }
+        assert_eq!(IndexFormat::detect(&binary), IndexFormat::Binary);
+
+        assert!(read_text_index(Cursor::new("0 120\n120\n")).is_err());
+        assert!(read_text_index(Cursor::new("0 x\n")).is_err());
+    }
+}
@@ -1,9 +1,15 @@
 pub mod async_reader;
+pub mod buffer_pool;
+pub mod compression;
 pub mod constants;
 pub mod crc32c;
 pub mod error;
+pub mod indexed_reader;
 pub mod indexing;
+pub mod mmap_reader;
 pub mod prelude;
+pub mod record;
+pub mod sharded_writer;
 pub mod sync_reader;
 pub mod sync_writer;
 pub mod tensorflow;
@@ -0,0 +1,273 @@
+use std::{fs::File, ops::Range, path::Path};
+
+use bytes::Bytes;
+use memmap2::Mmap;
+
+use crate::{
+    constants::{DEFAULT_MAX_RECORD_LENGTH, HEADER_SIZE, U32_SIZE, U64_SIZE},
+    crc32c::verify_masked_crc,
+    error::{Error, Result},
+    indexing::{
+        cache::{open_or_build_index, IndexCache},
+        source::IndexSource,
+        sync_reader::MmapIndexReader,
+    },
+    record::{check_length, read_u32},
+};
+
+/// Map the whole tfrecord file and hand out records without copying.
+///
+/// Records are [`Bytes`] sharing the map, so they stay valid after the reader is dropped.
+pub struct MmapTfrecordReader {
+    data: Bytes,
+    position: usize,
+    check_integrity: bool,
+    max_record_length: u64,
+    index: Option<Box<dyn IndexSource + Send + Sync>>,
+}
+
+impl MmapTfrecordReader {
+    pub fn open<P: AsRef<Path>>(path: P, check_integrity: bool) -> Result<Self> {
+        let file = File::open(path)?;
+        Self::new(&file, check_integrity)
+    }
+
+    /// Open with the index, default index path is `<file>.tfrecord.idx`, fails if the index is stale.
+    pub fn open_with_index<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        index_path: Option<Q>,
+        check_integrity: bool,
+    ) -> Result<Self> {
+        let index_path = index_path
+            .map(|p| p.as_ref().to_owned())
+            .unwrap_or_else(|| path.as_ref().with_extension("tfrecord.idx"));
+        let file = File::open(path)?;
+        let index = MmapIndexReader::open(index_path)?;
+        index.check_data(&file)?;
+        let mut reader = Self::new(&file, check_integrity)?;
+        reader.set_index(index);
+        Ok(reader)
+    }
+
+    /// Like [`MmapTfrecordReader::open_with_index`], but build a missing or stale index,
+    /// see [`open_or_build_index`].
+    pub fn open_or_build_index<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        index_path: Option<Q>,
+        cache: &IndexCache,
+        check_integrity: bool,
+    ) -> Result<Self> {
+        let index = open_or_build_index(
+            path.as_ref(),
+            index_path.as_ref().map(|p| p.as_ref()),
+            cache,
+        )?;
+        let mut reader = Self::open(path, check_integrity)?;
+        reader.set_index(index);
+        Ok(reader)
+    }
+
+    pub fn new(file: &File, check_integrity: bool) -> Result<Self> {
+        let mmap = unsafe { Mmap::map(file)? };
+        Ok(Self {
+            data: Bytes::from_owner(mmap),
+            position: 0,
+            check_integrity,
+            max_record_length: DEFAULT_MAX_RECORD_LENGTH,
+            index: None,
+        })
+    }
+
+    /// Any [`IndexSource`] describing this file, it is not checked against the data.
+    pub fn set_index<I: IndexSource + Send + Sync + 'static>(&mut self, index: I) {
+        self.index = Some(Box::new(index));
+    }
+
+    pub fn set_check_integrity(&mut self, check_integrity: bool) {
+        self.check_integrity = check_integrity;
+    }
+
+    /// A header with a larger data length is treated as corrupted.
+    pub fn set_max_record_length(&mut self, max_record_length: u64) {
+        self.max_record_length = max_record_length;
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position as u64
+    }
+
+    pub fn seek(&mut self, position: u64) {
+        self.position = position as usize;
+    }
+
+    /// Read the next record sequentially, the reader ends after an error.
+    pub fn read(&mut self) -> Result<Option<Bytes>> {
+        if self.position >= self.data.len() {
+            return Ok(None);
+        }
+        let data_range = match self.locate(self.position) {
+            Ok(data_range) => data_range,
+            Err(err) => {
+                self.position = self.data.len();
+                return Err(err);
+            }
+        };
+        self.position = data_range.end + U32_SIZE;
+        Ok(Some(self.data.slice(data_range)))
+    }
+
+    /// Read the record whose header starts at `offset`.
+    pub fn read_at(&self, offset: u64) -> Result<Bytes> {
+        let data_range = self.locate(offset as usize)?;
+        Ok(self.data.slice(data_range))
+    }
+
+    /// Same as [`MmapTfrecordReader::read_at`], but borrow from the map.
+    pub fn slice_at(&self, offset: u64) -> Result<&[u8]> {
+        let data_range = self.locate(offset as usize)?;
+        Ok(&self.data[data_range])
+    }
+
+    /// Number of records in the index.
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.index()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Read the `index`-th record through the index.
+    pub fn get(&self, index: usize) -> Result<Option<Bytes>> {
+        match self.index()?.get(index) {
+            Some((offset, _length)) => self.read_at(offset).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn index(&self) -> Result<&(dyn IndexSource + Send + Sync)> {
+        self.index.as_deref().ok_or(Error::MissingIndex)
+    }
+
+    /// Parse the header at `offset` and return the range of the data.
+    fn locate(&self, offset: usize) -> Result<Range<usize>> {
+        let truncated = || Error::DataLoss(format!("truncated record at offset {offset}"));
+
+        let header = offset
+            .checked_add(HEADER_SIZE)
+            .and_then(|header_end| self.data.get(offset..header_end))
+            .ok_or_else(truncated)?;
+        let length_buf = &header[..U64_SIZE];
+        if self.check_integrity {
+            verify_masked_crc(length_buf, read_u32(&header[U64_SIZE..]))?;
+        }
+
+        let length = u64::from_le_bytes(length_buf.try_into().unwrap());
+        check_length(length, self.max_record_length, offset as u64)?;
+
+        // the header is in the map, so the data and crc can be compared with the rest
+        let data_start = offset + HEADER_SIZE;
+        let available = (self.data.len() - data_start)
+            .checked_sub(U32_SIZE)
+            .ok_or_else(truncated)?;
+        if length > available as u64 {
+            return Err(truncated());
+        }
+        let data_end = data_start + length as usize;
+
+        if self.check_integrity {
+            verify_masked_crc(
+                &self.data[data_start..data_end],
+                read_u32(&self.data[data_end..]),
+            )?;
+        }
+
+        Ok(data_start..data_end)
+    }
+}
+
+impl Iterator for MmapTfrecordReader {
+    type Item = Result<Bytes>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync_writer::{OpenMode, TfrecordWriter};
+
+    #[test]
+    fn sequential_and_indexed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let records: Vec<Vec<u8>> = (0..10).map(|i| vec![i as u8; 100 + i]).collect();
+
+        let mut writer =
+            TfrecordWriter::create_with_index(&path, None::<&Path>, OpenMode::Truncate).unwrap();
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let reader = MmapTfrecordReader::open_with_index(&path, None::<&Path>, true).unwrap();
+        assert_eq!(reader.len().unwrap(), records.len());
+        for (i, record) in records.iter().enumerate().rev() {
+            assert_eq!(reader.get(i).unwrap().unwrap(), record.as_slice());
+        }
+        assert!(reader.get(records.len()).unwrap().is_none());
+
+        let decoded: Vec<Bytes> = reader.map(|record| record.unwrap()).collect();
+        assert_eq!(decoded, records);
+    }
+
+    #[test]
+    fn out_of_range_offsets_are_data_loss() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let mut writer = TfrecordWriter::create(&path).unwrap();
+        writer.write(&[1; 10]).unwrap();
+        writer.flush().unwrap();
+        drop(writer);
+
+        // a header claiming a huge length
+        let mut buf = std::fs::read(&path).unwrap();
+        buf[..U64_SIZE].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        std::fs::write(&path, buf).unwrap();
+
+        let mut reader = MmapTfrecordReader::open(&path, false).unwrap();
+        reader.set_max_record_length(u64::MAX);
+        assert!(matches!(reader.read_at(0), Err(Error::DataLoss(_))));
+        assert!(matches!(
+            reader.read_at(u64::MAX - 4),
+            Err(Error::DataLoss(_))
+        ));
+        reader.seek(u64::MAX - 4);
+        assert!(reader.read().unwrap().is_none());
+    }
+
+    #[test]
+    fn truncated_file_ends_iteration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let mut writer = TfrecordWriter::create(&path).unwrap();
+        for _ in 0..3 {
+            writer.write(&[1; 10]).unwrap();
+        }
+        writer.flush().unwrap();
+        drop(writer);
+
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        let len = file.metadata().unwrap().len();
+        file.set_len(len - 5).unwrap();
+
+        let mut reader = MmapTfrecordReader::open(&path, true).unwrap();
+        assert_eq!(reader.next().unwrap().unwrap(), [1; 10].as_slice());
+        assert_eq!(reader.next().unwrap().unwrap(), [1; 10].as_slice());
+        assert!(matches!(reader.next(), Some(Err(Error::DataLoss(_)))));
+        assert!(reader.next().is_none());
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,90 @@
+use std::{fs::File, ops::Range, os::unix::fs::FileExt};
+
+use crate::{
+    constants::{HEADER_SIZE, U32_SIZE, U64_SIZE},
+    crc32c::verify_masked_crc,
+    error::{Error, Result},
+};
+
+#[inline]
+pub fn read_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes(buf[..U32_SIZE].try_into().unwrap())
+}
+
+#[inline]
+pub fn read_u64(buf: &[u8]) -> u64 {
+    u64::from_le_bytes(buf[..U64_SIZE].try_into().unwrap())
+}
+
+/// Size of the whole record with header and crc of data.
+#[inline]
+pub fn record_size(data_length: u64) -> u64 {
+    (HEADER_SIZE + U32_SIZE) as u64 + data_length
+}
+
+/// Reject lengths from corrupted headers before allocating for them.
+pub fn check_length(length: u64, max_length: u64, offset: u64) -> Result<()> {
+    if length > max_length {
+        Err(Error::DataLoss(format!(
+            "record length {length} at offset {offset} exceeds the limit {max_length}"
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse a header and return the length of the data.
+pub fn parse_header(header: &[u8], check_integrity: bool) -> Result<u64> {
+    if check_integrity {
+        verify_masked_crc(&header[..U64_SIZE], read_u32(&header[U64_SIZE..]))?;
+    }
+    Ok(read_u64(header))
+}
+
+/// Check a whole record, `offset` is only for error messages.
+/// Return the range of the data in `buf`.
+pub fn parse_record(buf: &[u8], offset: u64, check_integrity: bool) -> Result<Range<usize>> {
+    if (buf.len() as u64) < record_size(0) {
+        return Err(Error::DataLoss(format!(
+            "truncated record at offset {offset}"
+        )));
+    }
+
+    let length = parse_header(buf, check_integrity)?;
+    if record_size(length) != buf.len() as u64 {
+        return Err(Error::DataLoss(format!(
+            "record length {length} at offset {offset} doesn't match the index ({})",
+            buf.len()
+        )));
+    }
+
+    let data_range = HEADER_SIZE..buf.len() - U32_SIZE;
+    if check_integrity {
+        verify_masked_crc(&buf[data_range.clone()], read_u32(&buf[data_range.end..]))?;
+    }
+    Ok(data_range)
+}
+
+/// Read the whole record of an index entry with one `pread` and return the data.
+pub fn read_record_at(
+    file: &File,
+    offset: u64,
+    length: u64,
+    check_integrity: bool,
+    max_record_length: u64,
+) -> Result<Vec<u8>> {
+    check_length(
+        length.saturating_sub(record_size(0)),
+        max_record_length,
+        offset,
+    )?;
+
+    let mut buf = vec![0; length as usize];
+    file.read_exact_at(&mut buf, offset)?;
+
+    let data_range = parse_record(&buf, offset, check_integrity)?;
+    let data_length = data_range.len();
+    buf.copy_within(data_range, 0);
+    buf.truncate(data_length);
+    Ok(buf)
+}
@@ -0,0 +1,300 @@
+use std::{
+    fs::File,
+    io::{BufWriter, ErrorKind},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{
+    compression::RecordCompression,
+    error::{Error, Result},
+    record::record_size,
+    sync_writer::{OpenMode, TfrecordWriter},
+};
+
+/// Name of shards before the total number is known.
+const UNKNOWN_NUM_SHARDS: &str = "unknown";
+
+/// Write records into shards, a new shard is started after `max_records` records
+/// or `max_bytes` bytes.
+///
+/// Shards are named by a template with `{shard}` and `{num_shards}`, optionally zero padded
+/// like `{shard:05}`. Until [`ShardedTfrecordWriter::close`], `{num_shards}` is `unknown`.
+/// The template must contain `{shard}`, otherwise all shards would have the same name.
+/// `write` takes `&self`, so the writer can be shared between threads.
+pub struct ShardedTfrecordWriter {
+    dir: PathBuf,
+    template: String,
+    max_records: Option<usize>,
+    max_bytes: Option<u64>,
+    write_index: bool,
+    record_compression: Option<RecordCompression>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    writer: Option<TfrecordWriter<BufWriter<File>>>,
+    num_records: usize,
+    shards: Vec<PathBuf>,
+}
+
+impl ShardedTfrecordWriter {
+    pub fn new<P: AsRef<Path>>(dir: P, template: &str) -> Result<Self> {
+        // Fail early on a bad template
+        if render_template(template, 0, None)? == render_template(template, 1, None)? {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("shard name template without {{shard}}: {template}"),
+            )
+            .into());
+        }
+        Ok(Self {
+            dir: dir.as_ref().to_owned(),
+            template: template.to_owned(),
+            max_records: None,
+            max_bytes: None,
+            write_index: false,
+            record_compression: None,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    pub fn set_max_records(&mut self, max_records: Option<usize>) {
+        self.max_records = max_records;
+    }
+
+    /// A shard may exceed this if a single record is larger.
+    pub fn set_max_bytes(&mut self, max_bytes: Option<u64>) {
+        self.max_bytes = max_bytes;
+    }
+
+    /// Write `<shard>.idx` next to every shard, `<shard>` being its full file name.
+    pub fn set_write_index(&mut self, write_index: bool) {
+        self.write_index = write_index;
+    }
+
+    pub fn set_record_compression(&mut self, record_compression: Option<RecordCompression>) {
+        self.record_compression = record_compression;
+    }
+
+    /// Write a record and return `(shard, offset, length)`.
+    pub fn write(&self, buf: &[u8]) -> Result<(usize, u64, u64)> {
+        // Compress outside the lock
+        let compressed;
+        let buf = match self.record_compression {
+            Some(record_compression) => {
+                compressed = record_compression.compress(buf)?;
+                &compressed[..]
+            }
+            None => buf,
+        };
+
+        let mut state = self.state.lock().unwrap();
+        let is_full = match state.writer {
+            Some(ref writer) => {
+                self.max_records
+                    .is_some_and(|max_records| state.num_records >= max_records)
+                    || self.max_bytes.is_some_and(|max_bytes| {
+                        writer.position() + record_size(buf.len() as u64) > max_bytes
+                    })
+            }
+            None => true,
+        };
+
+        let finished = if is_full {
+            self.rotate(&mut state)?
+        } else {
+            None
+        };
+
+        let shard = state.shards.len() - 1;
+        let (offset, length) = state.writer.as_mut().unwrap().write(buf)?;
+        state.num_records += 1;
+        drop(state);
+
+        // Sync the full shard without blocking other writers
+        if let Some(writer) = finished {
+            writer.finish()?;
+        }
+        Ok((shard, offset, length))
+    }
+
+    /// Start the next shard and return the writer of the full one, which the caller
+    /// finishes after releasing the lock.
+    fn rotate(&self, state: &mut State) -> Result<Option<TfrecordWriter<BufWriter<File>>>> {
+        let shard = state.shards.len();
+        let path = self.dir.join(render_template(&self.template, shard, None)?);
+        let writer = if self.write_index {
+            TfrecordWriter::create_with_index(&path, Some(&index_path(&path)), OpenMode::Atomic)?
+        } else {
+            TfrecordWriter::create_with_mode(&path, OpenMode::Atomic)?
+        };
+
+        let finished = state.writer.replace(writer);
+        state.num_records = 0;
+        state.shards.push(path);
+        Ok(finished)
+    }
+
+    /// Finish the last shard and rename all shards with the final number of shards.
+    pub fn close(self) -> Result<Vec<PathBuf>> {
+        let mut state = self.state.into_inner().unwrap();
+        if let Some(writer) = state.writer.take() {
+            writer.finish()?;
+        }
+
+        let num_shards = state.shards.len();
+        let mut paths = Vec::with_capacity(num_shards);
+        for (shard, old_path) in state.shards.into_iter().enumerate() {
+            let path = self
+                .dir
+                .join(render_template(&self.template, shard, Some(num_shards))?);
+            if path != old_path {
+                std::fs::rename(&old_path, &path)?;
+                if self.write_index {
+                    std::fs::rename(index_path(&old_path), index_path(&path))?;
+                }
+            }
+            paths.push(path);
+        }
+
+        File::open(&self.dir)?.sync_all()?;
+        Ok(paths)
+    }
+}
+
+/// Append `.idx` to the whole file name, `with_extension` would give shards named like
+/// `train.tfrecord-00000-of-00010` the same index.
+fn index_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".idx");
+    PathBuf::from(name)
+}
+
+/// Replace `{shard}` and `{num_shards}`, `{shard:05}` pads with zeros.
+pub fn render_template(template: &str, shard: usize, num_shards: Option<usize>) -> Result<String> {
+    let invalid = || {
+        Error::from(std::io::Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid shard name template: {template}"),
+        ))
+    };
+
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(invalid)? + start;
+        let (name, width) = match rest[start + 1..end].split_once(':') {
+            Some((name, width)) => (name, width.parse::<usize>().map_err(|_| invalid())?),
+            None => (&rest[start + 1..end], 0),
+        };
+
+        match (name, num_shards) {
+            ("shard", _) => out.push_str(&format!("{shard:0width$}")),
+            ("num_shards", Some(num_shards)) => out.push_str(&format!("{num_shards:0width$}")),
+            ("num_shards", None) => out.push_str(UNKNOWN_NUM_SHARDS),
+            _ => return Err(invalid()),
+        }
+        rest = &rest[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        indexing::{source::IndexSource, sync_reader::IndexReader},
+        sync_reader::TfrecordReader,
+    };
+
+    #[test]
+    fn template() {
+        let template = "train-{shard:05}-of-{num_shards:05}.tfrecord";
+        assert_eq!(
+            render_template(template, 3, Some(10)).unwrap(),
+            "train-00003-of-00010.tfrecord"
+        );
+        assert_eq!(
+            render_template(template, 3, None).unwrap(),
+            "train-00003-of-unknown.tfrecord"
+        );
+        assert!(matches!(
+            render_template("{shard", 0, None),
+            Err(Error::IoError(err)) if err.kind() == ErrorKind::InvalidInput
+        ));
+        assert!(render_template("{index}", 0, None).is_err());
+
+        let dir = tempfile::tempdir().unwrap();
+        assert!(matches!(
+            ShardedTfrecordWriter::new(dir.path(), "train-of-{num_shards}.tfrecord"),
+            Err(Error::IoError(err)) if err.kind() == ErrorKind::InvalidInput
+        ));
+    }
+
+    fn write_shards(
+        template: &str,
+        max_records: Option<usize>,
+        max_bytes: Option<u64>,
+    ) -> Vec<usize> {
+        let dir = tempfile::tempdir().unwrap();
+        let mut writer = ShardedTfrecordWriter::new(dir.path(), template).unwrap();
+        writer.set_max_records(max_records);
+        writer.set_max_bytes(max_bytes);
+        writer.set_write_index(true);
+
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    for _ in 0..5 {
+                        writer.write(&[0; 100]).unwrap();
+                    }
+                });
+            }
+        });
+
+        let paths = writer.close().unwrap();
+        for (shard, path) in paths.iter().enumerate() {
+            let name = render_template(template, shard, Some(paths.len())).unwrap();
+            assert_eq!(path.file_name().unwrap().to_str().unwrap(), name);
+        }
+        assert_eq!(
+            std::fs::read_dir(dir.path()).unwrap().count(),
+            paths.len() * 2
+        );
+
+        paths
+            .iter()
+            .map(|path| {
+                let count = TfrecordReader::open(path, true).unwrap().count();
+                let index = IndexReader::open(index_path(path)).unwrap();
+                assert_eq!(index.len(), count);
+                count
+            })
+            .collect()
+    }
+
+    const TEMPLATE: &str = "{shard:02}-of-{num_shards:02}.tfrecord";
+
+    #[test]
+    fn rotate_by_count() {
+        assert_eq!(write_shards(TEMPLATE, Some(4), None), [4, 4, 2]);
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        assert_eq!(
+            write_shards(TEMPLATE, None, Some(3 * record_size(100))),
+            [3, 3, 3, 1]
+        );
+    }
+
+    #[test]
+    fn dotted_template() {
+        let template = "mnist-train.tfrecord-{shard:05}-of-{num_shards:05}";
+        assert_eq!(write_shards(template, Some(4), None), [4, 4, 2]);
+    }
+}
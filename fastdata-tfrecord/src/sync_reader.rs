@@ -1,79 +1,237 @@
 use std::{
     fs::File,
-    io::{BufReader, Read, Seek},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    ops::Range,
     path::Path,
 };
 
 use crate::{
-    constants::{U32_SIZE, U64_SIZE},
+    compression::{Compression, Decoder, RecordCompression},
+    constants::{DEFAULT_MAX_RECORD_LENGTH, HEADER_SIZE, U32_SIZE, U64_SIZE},
     crc32c::verify_masked_crc,
-    error::Result,
+    error::{Error, Result},
+    record::{check_length, parse_header, read_u32, read_u64, record_size},
 };
 
+const RESYNC_CHUNK_SIZE: usize = 64 * 1024;
+/// Smaller payloads are read and dropped instead of seeking over them,
+/// a seek throws away the buffer of a `BufReader`.
+const SEEK_THRESHOLD: u64 = 64 * 1024;
+
 pub struct TfrecordReader<T> {
-    reader: T,
+    reader: PushbackReader<T>,
     check_integrity: bool,
-    length_buf: [u8; U64_SIZE],
-    masked_crc_buf: [u8; U32_SIZE],
-    data_buf: Vec<u8>,
+    record_compression: bool,
+    resync: bool,
+    max_record_length: u64,
+    on_skip: Option<Box<dyn FnMut(Range<u64>) + Send>>,
+    skipped_bytes: u64,
+    // header, data and crc of data for the current record
+    record_buf: Vec<u8>,
+    filled: usize,
+    decompressed_buf: Vec<u8>,
 }
 
 impl<T: Read> TfrecordReader<T> {
     pub fn new(reader: T, check_integrity: bool) -> Self {
         Self {
-            reader,
+            reader: PushbackReader::new(reader),
             check_integrity,
-            length_buf: [0; U64_SIZE],
-            masked_crc_buf: [0; U32_SIZE],
-            data_buf: Vec::with_capacity(1024),
+            record_compression: false,
+            resync: false,
+            max_record_length: DEFAULT_MAX_RECORD_LENGTH,
+            on_skip: None,
+            skipped_bytes: 0,
+            record_buf: vec![0; 1024],
+            filled: 0,
+            decompressed_buf: Vec::new(),
         }
     }
 
     pub fn read(&mut self) -> Result<Option<Vec<u8>>> {
-        match self.reader.read_exact(&mut self.length_buf) {
-            Ok(_) => {}
-            Err(err) => {
-                return match err.kind() {
-                    std::io::ErrorKind::UnexpectedEof => Ok(None),
-                    _ => Err(err.into()),
+        let mut buf = Vec::new();
+        Ok(self.read_into(&mut buf)?.map(|_| buf))
+    }
+
+    /// Read the next record into `buf` and return its length, `buf` is cleared first.
+    pub fn read_into(&mut self, buf: &mut Vec<u8>) -> Result<Option<usize>> {
+        let length = match self.read_record()? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+
+        let data_buf = &self.record_buf[HEADER_SIZE..HEADER_SIZE + length];
+        if self.record_compression {
+            RecordCompression::decompress_into(data_buf, buf, self.max_record_length)?;
+        } else {
+            buf.clear();
+            buf.extend_from_slice(data_buf);
+        }
+        Ok(Some(buf.len()))
+    }
+
+    /// Borrow the next record from the internal buffer without copying it.
+    pub fn next_record(&mut self) -> Result<Option<&[u8]>> {
+        let length = match self.read_record()? {
+            Some(length) => length,
+            None => return Ok(None),
+        };
+
+        let data_buf = &self.record_buf[HEADER_SIZE..HEADER_SIZE + length];
+        if self.record_compression {
+            RecordCompression::decompress_into(
+                data_buf,
+                &mut self.decompressed_buf,
+                self.max_record_length,
+            )?;
+            Ok(Some(&self.decompressed_buf))
+        } else {
+            Ok(Some(data_buf))
+        }
+    }
+
+    /// Read the next record into `record_buf` and return the length of its data.
+    fn read_record(&mut self) -> Result<Option<usize>> {
+        loop {
+            let record_start = self.reader.offset;
+            match self.try_read_record() {
+                Ok(length) => return Ok(length),
+                Err(Error::ChecksumMismatch { .. } | Error::DataLoss(_)) if self.resync => {
+                    self.resync(record_start)?;
                 }
+                Err(err) => return Err(err),
             }
         }
+    }
 
-        self.reader.read_exact(&mut self.masked_crc_buf)?;
+    fn try_read_record(&mut self) -> Result<Option<usize>> {
+        let verify = self.check_integrity || self.resync;
+        self.filled = 0;
 
-        if self.check_integrity {
-            self.verify_masked_crc32(&self.length_buf)
-                .expect("fail to pass length crc");
+        self.fill_record_buf(HEADER_SIZE)?;
+        if self.filled == 0 {
+            return Ok(None);
+        }
+        if self.filled < HEADER_SIZE {
+            return Err(self.truncated());
         }
 
-        let length = u64::from_le_bytes(self.length_buf);
-        // dbg!(length);
-
-        if length as usize > self.data_buf.len() {
-            self.data_buf.resize((length * 2) as usize, 0);
+        let length_buf = &self.record_buf[..U64_SIZE];
+        if verify {
+            verify_masked_crc(length_buf, read_u32(&self.record_buf[U64_SIZE..]))?;
         }
 
-        self.reader
-            .read_exact(&mut self.data_buf[..length as usize])?;
+        let length = u64::from_le_bytes(length_buf.try_into().unwrap());
+        check_length(
+            length,
+            self.max_record_length,
+            self.reader.offset - HEADER_SIZE as u64,
+        )?;
+
+        let length = length as usize;
+        let record_size = HEADER_SIZE + length + U32_SIZE;
+
+        if record_size > self.record_buf.len() {
+            self.record_buf.resize(record_size, 0);
+        }
 
-        self.reader.read_exact(&mut self.masked_crc_buf)?;
+        self.fill_record_buf(record_size)?;
+        if self.filled < record_size {
+            return Err(self.truncated());
+        }
 
-        if self.check_integrity {
-            self.verify_masked_crc32(&self.data_buf[..length as usize])?;
+        if verify {
+            let data_buf = &self.record_buf[HEADER_SIZE..HEADER_SIZE + length];
+            verify_masked_crc(data_buf, read_u32(&self.record_buf[HEADER_SIZE + length..]))?;
         }
 
-        Ok(Some(self.data_buf[..length as usize].to_owned()))
+        Ok(Some(length))
+    }
+
+    fn fill_record_buf(&mut self, end: usize) -> Result<()> {
+        self.filled += read_full(&mut self.reader, &mut self.record_buf[self.filled..end])?;
+        Ok(())
+    }
+
+    fn truncated(&self) -> Error {
+        Error::DataLoss(format!(
+            "truncated record at offset {}",
+            self.reader.offset - self.filled as u64
+        ))
+    }
+
+    /// Scan forward from the byte after `record_start` until the next valid header
+    /// and push the scanned bytes back, so the next read starts at that header.
+    fn resync(&mut self, record_start: u64) -> Result<()> {
+        let mut window = self.record_buf[1..self.filled].to_vec();
+        let mut window_start = record_start + 1;
+
+        loop {
+            if let Some(pos) = find_header(&window, self.max_record_length) {
+                self.reader.unread(&window[pos..]);
+                self.report_skipped(record_start..window_start + pos as u64);
+                return Ok(());
+            }
+
+            // Keep the tail, it may be the beginning of a header
+            let drained = window.len().saturating_sub(HEADER_SIZE - 1);
+            window.drain(..drained);
+            window_start += drained as u64;
+
+            let old_len = window.len();
+            window.resize(old_len + RESYNC_CHUNK_SIZE, 0);
+            let n = read_full(&mut self.reader, &mut window[old_len..])?;
+            window.truncate(old_len + n);
+
+            if n == 0 {
+                self.report_skipped(record_start..window_start + window.len() as u64);
+                return Ok(());
+            }
+        }
     }
 
-    fn verify_masked_crc32(&self, buf: &[u8]) -> Result<()> {
-        let expect = u32::from_le_bytes(self.masked_crc_buf);
-        verify_masked_crc(buf, expect)
+    fn report_skipped(&mut self, range: Range<u64>) {
+        self.skipped_bytes += range.end - range.start;
+        if let Some(on_skip) = self.on_skip.as_mut() {
+            on_skip(range);
+        }
     }
 
     pub fn set_check_integrity(&mut self, check_integrity: bool) {
         self.check_integrity = check_integrity;
     }
+
+    /// Decompress records written with [`RecordCompression`].
+    pub fn set_record_compression(&mut self, record_compression: bool) {
+        self.record_compression = record_compression;
+    }
+
+    /// Skip corrupted bytes instead of returning an error.
+    ///
+    /// After a checksum mismatch or a truncated record, the reader scans forward
+    /// for the next header whose length matches its masked CRC and continues from there.
+    /// CRCs are always verified in this mode.
+    pub fn set_resync(&mut self, resync: bool) {
+        self.resync = resync;
+    }
+
+    /// A header with a larger data length is treated as corrupted instead of allocating for it.
+    pub fn set_max_record_length(&mut self, max_record_length: u64) {
+        self.max_record_length = max_record_length;
+    }
+
+    /// Called with the byte range of every skipped region, offsets are relative
+    /// to where the reader started.
+    pub fn set_on_skip<F>(&mut self, on_skip: F)
+    where
+        F: FnMut(Range<u64>) + Send + 'static,
+    {
+        self.on_skip = Some(Box::new(on_skip));
+    }
+
+    pub fn skipped_bytes(&self) -> u64 {
+        self.skipped_bytes
+    }
 }
 
 impl TfrecordReader<BufReader<File>> {
@@ -83,6 +241,37 @@ impl TfrecordReader<BufReader<File>> {
     }
 }
 
+impl<T: BufRead> TfrecordReader<Decoder<T>> {
+    pub fn with_compression(reader: T, compression: Compression, check_integrity: bool) -> Self {
+        Self::new(Decoder::new(reader, compression), check_integrity)
+    }
+}
+
+impl TfrecordReader<Decoder<BufReader<File>>> {
+    pub fn open_with_compression<P: AsRef<Path>>(
+        path: P,
+        compression: Compression,
+        check_integrity: bool,
+    ) -> Result<Self> {
+        let file = File::open(path)?;
+        Ok(Self::with_compression(
+            BufReader::new(file),
+            compression,
+            check_integrity,
+        ))
+    }
+
+    /// Detect compression from the file extension, then from the magic bytes.
+    pub fn open_auto<P: AsRef<Path>>(path: P, check_integrity: bool) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(&path)?);
+        let compression = match Compression::from_path(&path) {
+            Some(compression) => compression,
+            None => Compression::detect(&mut reader)?,
+        };
+        Ok(Self::with_compression(reader, compression, check_integrity))
+    }
+}
+
 impl<T: Read + Seek> TfrecordReader<T> {
     // pub fn seek(&mut self, pos: u64) -> Result<()> {
     //     self.reader.seek(std::io::SeekFrom::Start(pos))?;
@@ -90,21 +279,104 @@ impl<T: Read + Seek> TfrecordReader<T> {
     // }
 
     pub fn position(&mut self) -> Result<u64> {
-        self.reader.stream_position().map_err(|err| err.into())
+        let position = self.reader.inner.stream_position()?;
+        Ok(position - self.reader.buffered() as u64)
     }
 
+    /// Read the whole record and return its index entry, the data crc is verified
+    /// if `check_integrity` is set.
     pub fn read_index(&mut self) -> Result<Option<(u64, u64)>> {
-        let start_position = self.position()?;
-        if let Some(_) = self.read()? {
-            let end_position = self.position()?;
-            Ok(Some((start_position, end_position - start_position)))
+        match self.read_record()? {
+            Some(length) => {
+                let record_size = (HEADER_SIZE + length + U32_SIZE) as u64;
+                let end_position = self.position()?;
+                Ok(Some((end_position - record_size, record_size)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Read only the header and seek over the data, return the index entry of the record.
+    ///
+    /// The crc of the header is verified if `check_integrity` is set, the data is never read.
+    /// A record cut off by the end of the file is still detected. Resync doesn't apply here.
+    pub fn skip_index(&mut self) -> Result<Option<(u64, u64)>> {
+        let offset = self.position()?;
+        self.filled = 0;
+        self.fill_record_buf(HEADER_SIZE)?;
+        if self.filled == 0 {
+            return Ok(None);
+        }
+        if self.filled < HEADER_SIZE {
+            return Err(self.truncated());
+        }
+
+        let length = parse_header(&self.record_buf[..HEADER_SIZE], self.check_integrity)?;
+        check_length(length, self.max_record_length, offset)?;
+
+        // land on the crc of data and read it, a short read means the record is truncated
+        let data_crc_start = self.reader.offset + length;
+        if length <= SEEK_THRESHOLD {
+            self.discard(length)?;
         } else {
-            Ok(None)
+            self.reader.seek_forward(length)?;
+        }
+        let mut crc_buf = [0; U32_SIZE];
+        if self.reader.offset != data_crc_start
+            || read_full(&mut self.reader, &mut crc_buf)? < U32_SIZE
+        {
+            return Err(Error::DataLoss(format!(
+                "truncated record at offset {offset}"
+            )));
         }
+
+        Ok(Some((offset, record_size(length))))
+    }
+
+    /// Skip `n` records without reading their data, see [`TfrecordReader::skip_index`].
+    ///
+    /// Return the number of records skipped, which is less than `n` at the end of the file.
+    /// Not named `skip` to not be shadowed by [`Iterator::skip`].
+    pub fn skip_records(&mut self, n: usize) -> Result<usize> {
+        for i in 0..n {
+            if self.skip_index()?.is_none() {
+                return Ok(i);
+            }
+        }
+        Ok(n)
+    }
+
+    /// Count the remaining records from their headers.
+    pub fn count_records(&mut self) -> Result<usize> {
+        self.skip_records(usize::MAX)
     }
 
+    fn discard(&mut self, mut n: u64) -> Result<()> {
+        while n > 0 {
+            let chunk = n.min(self.record_buf.len() as u64) as usize;
+            let read = read_full(&mut self.reader, &mut self.record_buf[..chunk])?;
+            if read == 0 {
+                break;
+            }
+            n -= read as u64;
+        }
+        Ok(())
+    }
+
+    /// Index entries from headers only, see [`TfrecordReader::skip_index`].
     pub fn indices(self) -> Indices<T> {
-        Indices { reader: self }
+        Indices {
+            reader: self,
+            read_data: false,
+        }
+    }
+
+    /// Index entries of whole records, data crcs are verified if `check_integrity` is set.
+    pub fn verified_indices(self) -> Indices<T> {
+        Indices {
+            reader: self,
+            read_data: true,
+        }
     }
 }
 
@@ -124,12 +396,272 @@ impl<T: Read> Iterator for TfrecordReader<T> {
 
 pub struct Indices<T> {
     reader: TfrecordReader<T>,
+    read_data: bool,
 }
 
 impl<T: Read + Seek> Iterator for Indices<T> {
     type Item = Result<(u64, u64)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.reader.read_index().transpose()
+        if self.read_data {
+            self.reader.read_index().transpose()
+        } else {
+            self.reader.skip_index().transpose()
+        }
+    }
+}
+
+/// Bytes pushed back by resync are read again before the inner reader.
+struct PushbackReader<T> {
+    inner: T,
+    pending: Vec<u8>,
+    pending_pos: usize,
+    // bytes consumed from the start of the reader
+    offset: u64,
+}
+
+impl<T> PushbackReader<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            pending: Vec::new(),
+            pending_pos: 0,
+            offset: 0,
+        }
+    }
+
+    fn buffered(&self) -> usize {
+        self.pending.len() - self.pending_pos
+    }
+
+    fn unread(&mut self, buf: &[u8]) {
+        let mut pending = buf.to_vec();
+        pending.extend_from_slice(&self.pending[self.pending_pos..]);
+        self.pending = pending;
+        self.pending_pos = 0;
+        self.offset -= buf.len() as u64;
+    }
+}
+
+impl<T: Seek> PushbackReader<T> {
+    /// Consume `n` bytes, the offset may end up after the end of the file.
+    fn seek_forward(&mut self, n: u64) -> std::io::Result<()> {
+        let from_pending = (self.buffered() as u64).min(n);
+        self.pending_pos += from_pending as usize;
+        let rest = n - from_pending;
+        if rest > 0 {
+            self.inner.seek(SeekFrom::Current(rest as i64))?;
+        }
+        self.offset += n;
+        Ok(())
+    }
+}
+
+impl<T: Read> Read for PushbackReader<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = if self.buffered() > 0 {
+            let n = self.buffered().min(buf.len());
+            buf[..n].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + n]);
+            self.pending_pos += n;
+            n
+        } else {
+            self.inner.read(buf)?
+        };
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+/// Like `read_exact`, but return the number of bytes read on EOF.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(filled)
+}
+
+fn find_header(buf: &[u8], max_record_length: u64) -> Option<usize> {
+    buf.windows(HEADER_SIZE).position(|header| {
+        verify_masked_crc(&header[..U64_SIZE], read_u32(&header[U64_SIZE..])).is_ok()
+            && read_u64(header) <= max_record_length
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::sync_writer::TfrecordWriter;
+
+    fn make_records() -> (Vec<Vec<u8>>, Vec<u8>) {
+        let records: Vec<Vec<u8>> = (0..10).map(|i| vec![i as u8; 100 + i]).collect();
+        let mut writer = TfrecordWriter::new(Vec::new());
+        for record in &records {
+            writer.write(record).unwrap();
+        }
+        (records, writer.into_inner())
+    }
+
+    fn record_range(records: &[Vec<u8>], index: usize) -> Range<u64> {
+        let size = |record: &Vec<u8>| (HEADER_SIZE + record.len() + U32_SIZE) as u64;
+        let start = records[..index].iter().map(size).sum::<u64>();
+        start..start + size(&records[index])
+    }
+
+    #[test]
+    fn lending_read() {
+        let (records, buf) = make_records();
+        let mut reader = TfrecordReader::new(Cursor::new(buf), true);
+        for record in &records {
+            assert_eq!(reader.next_record().unwrap(), Some(record.as_slice()));
+        }
+        assert_eq!(reader.next_record().unwrap(), None);
+    }
+
+    #[test]
+    fn read_into_reuses_buffer() {
+        let (records, buf) = make_records();
+        let mut reader = TfrecordReader::new(Cursor::new(buf), true);
+        let mut record_buf = vec![0xff; 1000];
+        for record in &records {
+            assert_eq!(
+                reader.read_into(&mut record_buf).unwrap(),
+                Some(record.len())
+            );
+            assert_eq!(&record_buf, record);
+        }
+        assert_eq!(reader.read_into(&mut record_buf).unwrap(), None);
+    }
+
+    #[test]
+    fn corrupted_data_is_error() {
+        let (_records, mut buf) = make_records();
+        buf[HEADER_SIZE + 10] ^= 0xff;
+        let mut reader = TfrecordReader::new(Cursor::new(buf), true);
+        assert!(matches!(reader.read(), Err(Error::ChecksumMismatch { .. })));
+    }
+
+    #[test]
+    fn resync_skips_corrupted_records() {
+        let (records, mut buf) = make_records();
+        let data_corrupted = record_range(&records, 3);
+        let length_corrupted = record_range(&records, 6);
+        buf[data_corrupted.start as usize + HEADER_SIZE + 10] ^= 0xff;
+        buf[length_corrupted.start as usize + 1] ^= 0xff;
+
+        let skipped = Arc::new(Mutex::new(Vec::new()));
+        let mut reader = TfrecordReader::new(Cursor::new(buf), false);
+        reader.set_resync(true);
+        let on_skip = skipped.clone();
+        reader.set_on_skip(move |range| on_skip.lock().unwrap().push(range));
+
+        let decoded: Vec<Vec<u8>> = reader.by_ref().map(|record| record.unwrap()).collect();
+        let expected: Vec<Vec<u8>> = records
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 3 && *i != 6)
+            .map(|(_, record)| record.clone())
+            .collect();
+        assert_eq!(decoded, expected);
+        assert_eq!(
+            *skipped.lock().unwrap(),
+            vec![data_corrupted.clone(), length_corrupted.clone()]
+        );
+        assert_eq!(
+            reader.skipped_bytes(),
+            (data_corrupted.end - data_corrupted.start)
+                + (length_corrupted.end - length_corrupted.start)
+        );
+    }
+
+    #[test]
+    fn huge_length_is_data_loss() {
+        let (_records, mut buf) = make_records();
+        buf[..U64_SIZE].copy_from_slice(&u64::MAX.to_le_bytes());
+        let masked_crc = crate::crc32c::get_masked_crc(&buf[..U64_SIZE]);
+        buf[U64_SIZE..HEADER_SIZE].copy_from_slice(&masked_crc.to_le_bytes());
+
+        let mut reader = TfrecordReader::new(Cursor::new(buf), true);
+        match reader.read() {
+            Err(Error::DataLoss(message)) => assert!(message.contains("offset 0")),
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    #[test]
+    fn resync_skips_truncated_tail() {
+        let (records, mut buf) = make_records();
+        let last = record_range(&records, 9);
+        buf.truncate(last.start as usize + 20);
+
+        let mut reader = TfrecordReader::new(Cursor::new(buf), true);
+        reader.set_resync(true);
+        assert_eq!(reader.by_ref().count(), 9);
+        assert_eq!(reader.skipped_bytes(), 20);
+    }
+
+    #[test]
+    fn header_only_skip() {
+        let (records, buf) = make_records();
+        let expected: Vec<(u64, u64)> = (0..records.len())
+            .map(|i| {
+                let range = record_range(&records, i);
+                (range.start, range.end - range.start)
+            })
+            .collect();
+        let indices = TfrecordReader::new(Cursor::new(buf.clone()), true)
+            .indices()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(indices, expected);
+
+        let mut reader = TfrecordReader::new(Cursor::new(buf.clone()), true);
+        assert_eq!(reader.skip_records(3).unwrap(), 3);
+        assert_eq!(reader.read().unwrap(), Some(records[3].clone()));
+        assert_eq!(reader.count_records().unwrap(), 6);
+        assert_eq!(reader.skip_records(1).unwrap(), 0);
+
+        // the data crc is not read, a truncated record is still found
+        let mut corrupted = buf.clone();
+        corrupted[HEADER_SIZE + 10] ^= 0xff;
+        let mut reader = TfrecordReader::new(Cursor::new(corrupted), true);
+        assert_eq!(reader.count_records().unwrap(), 10);
+        let mut truncated = buf;
+        truncated.truncate(truncated.len() - 1);
+        let mut reader = TfrecordReader::new(Cursor::new(truncated), true);
+        assert!(matches!(reader.count_records(), Err(Error::DataLoss(_))));
+    }
+
+    #[test]
+    fn header_only_seeks_large_records() {
+        let sizes = [200 * 1024, 10, 100 * 1024];
+        let mut writer = TfrecordWriter::new(Vec::new());
+        for size in sizes {
+            writer.write(&vec![1; size]).unwrap();
+        }
+        let buf = writer.into_inner();
+
+        let mut reader = TfrecordReader::new(Cursor::new(buf.clone()), true);
+        assert_eq!(reader.skip_records(2).unwrap(), 2);
+        assert_eq!(
+            reader.read().unwrap().map(|record| record.len()),
+            Some(sizes[2])
+        );
+
+        let mut truncated = buf;
+        truncated.truncate(truncated.len() - 1000);
+        let mut reader = TfrecordReader::new(Cursor::new(truncated), true);
+        assert_eq!(reader.skip_records(2).unwrap(), 2);
+        assert!(matches!(reader.skip_records(1), Err(Error::DataLoss(_))));
     }
 }
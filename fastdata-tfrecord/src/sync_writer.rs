@@ -1,21 +1,177 @@
 use std::{
     fs::File,
-    io::{BufWriter, Write},
-    path::Path,
+    io::{BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
 };
 
-use crate::{crc32c::get_masked_crc, error::Result};
+use crate::{
+    compression::{Compression, Encoder, RecordCompression},
+    crc32c::get_masked_crc,
+    error::Result,
+    indexing::{
+        format::{split_index, DataInfo, ENTRY_SIZE},
+        sync_writer::SyncIndexWriter,
+    },
+    record::record_size,
+    sync_reader::TfrecordReader,
+};
+
+/// How `create_*` opens the data file and the index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OpenMode {
+    /// Fail if the file already exists.
+    CreateNew,
+    /// Create the file or truncate it.
+    #[default]
+    Truncate,
+    /// Create the file or append to it, offsets continue after the existing records.
+    Append,
+    /// Write to a hidden temporary file in the same directory,
+    /// which [`TfrecordWriter::finish`] renames into place.
+    Atomic,
+}
+
+/// A file opened by `create_*`, kept to sync and rename it in [`TfrecordWriter::finish`].
+struct OutputFile {
+    file: File,
+    path: PathBuf,
+    temp_path: Option<PathBuf>,
+}
+
+impl OutputFile {
+    fn open(path: &Path, mode: OpenMode) -> Result<Self> {
+        let mut options = File::options();
+        options.write(true);
+        let temp_path = match mode {
+            OpenMode::CreateNew => {
+                options.create_new(true);
+                None
+            }
+            OpenMode::Truncate => {
+                options.create(true).truncate(true);
+                None
+            }
+            OpenMode::Append => {
+                options.create(true).append(true);
+                None
+            }
+            OpenMode::Atomic => {
+                options.create(true).truncate(true);
+                Some(temp_path(path))
+            }
+        };
+
+        let file = options.open(temp_path.as_deref().unwrap_or(path))?;
+        Ok(Self {
+            file,
+            path: path.to_owned(),
+            temp_path,
+        })
+    }
+
+    fn commit(mut self) -> Result<()> {
+        self.file.sync_all()?;
+        if let Some(temp_path) = self.temp_path.clone() {
+            std::fs::rename(temp_path, &self.path)?;
+            self.temp_path = None;
+            let dir = match self.path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OutputFile {
+    /// Remove the temporary file of a writer dropped without `finish`.
+    fn drop(&mut self) {
+        if let Some(temp_path) = &self.temp_path {
+            let _ = std::fs::remove_file(temp_path);
+        }
+    }
+}
+
+/// `<dir>/.<file name>.<pid>.<n>.tmp`, `n` counts the calls so writers of the same
+/// file in one process don't share it.
+pub(crate) fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(
+        ".{}.{}.{}.tmp",
+        file_name,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Flush everything buffered and make it durable, called by [`TfrecordWriter::finish`].
+pub trait Finalize: Write {
+    fn finalize(&mut self) -> std::io::Result<()> {
+        self.flush()
+    }
+}
+
+impl Finalize for Vec<u8> {}
+
+impl Finalize for File {
+    fn finalize(&mut self) -> std::io::Result<()> {
+        self.sync_all()
+    }
+}
+
+impl<W: Finalize> Finalize for BufWriter<W> {
+    fn finalize(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        self.get_mut().finalize()
+    }
+}
+
+impl<W: Finalize> Finalize for Encoder<W> {
+    fn finalize(&mut self) -> std::io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().finalize()
+    }
+}
 
 pub struct TfrecordWriter<T> {
     writer: T,
+    record_compression: Option<RecordCompression>,
+    // uncompressed bytes written, including what was in the file before
+    position: u64,
+    // crc32c of everything written for the index footer, `None` if it can't be known
+    data_crc: Option<u32>,
+    index_writer: Option<SyncIndexWriter<Box<dyn Write + Send>>>,
+    output_files: Vec<OutputFile>,
 }
 
 impl<T: Write> TfrecordWriter<T> {
     pub fn new(writer: T) -> Self {
-        Self { writer }
+        Self {
+            writer,
+            record_compression: None,
+            position: 0,
+            data_crc: Some(0),
+            index_writer: None,
+            output_files: Vec::new(),
+        }
+    }
+
+    /// Write a record and return its `(offset, length)` as stored in the index.
+    pub fn write(&mut self, buf: &[u8]) -> Result<(u64, u64)> {
+        match self.record_compression {
+            Some(record_compression) => {
+                let compressed = record_compression.compress(buf)?;
+                self.write_record(&compressed)
+            }
+            None => self.write_record(buf),
+        }
     }
 
-    pub fn write(&mut self, buf: &[u8]) -> Result<()> {
+    fn write_record(&mut self, buf: &[u8]) -> Result<(u64, u64)> {
         let length = buf.len() as u64;
         let length_buf = length.to_le_bytes();
         let masked_crc_of_length = get_masked_crc(&length_buf);
@@ -28,11 +184,61 @@ impl<T: Write> TfrecordWriter<T> {
         self.writer.write_all(buf)?;
         self.writer.write_all(&masked_crc_of_data_buf)?;
 
-        Ok(())
+        if let Some(crc) = self
+            .data_crc
+            .as_mut()
+            .filter(|_| self.index_writer.is_some())
+        {
+            for part in [
+                &length_buf[..],
+                &masked_crc_of_length_buf,
+                buf,
+                &masked_crc_of_data_buf,
+            ] {
+                *crc = crc32c::crc32c_append(*crc, part);
+            }
+        }
+
+        let offset = self.position;
+        let length = record_size(length);
+        self.position += length;
+
+        if let Some(index_writer) = self.index_writer.as_mut() {
+            index_writer.write_index(offset, length)?;
+        }
+
+        Ok((offset, length))
     }
 
     pub fn flush(&mut self) -> Result<()> {
-        Ok(self.writer.flush()?)
+        self.writer.flush()?;
+        if let Some(index_writer) = self.index_writer.as_mut() {
+            index_writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Offset of the next record.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /// Write an index entry for every record, offsets are relative to the uncompressed stream.
+    pub fn set_index_writer<W: Write + Send + 'static>(&mut self, index_writer: W) {
+        // the crc is only kept with an index
+        if self.index_writer.is_none() && self.position > 0 {
+            self.data_crc = None;
+        }
+        self.index_writer = Some(SyncIndexWriter::new(Box::new(index_writer)));
+    }
+
+    /// Compress every record on its own, see [`RecordCompression`].
+    pub fn set_record_compression(&mut self, record_compression: Option<RecordCompression>) {
+        self.record_compression = record_compression;
+    }
+
+    pub fn into_inner(self) -> T {
+        self.writer
     }
 }
 
@@ -42,10 +248,295 @@ impl<T: Write> From<T> for TfrecordWriter<T> {
     }
 }
 
+impl<T: Finalize> TfrecordWriter<T> {
+    /// Flush and sync the data and the index, then rename temporary files into place.
+    ///
+    /// The index is sealed with a footer describing the data file, see
+    /// [`IndexFooter`](crate::indexing::format::IndexFooter).
+    /// A writer dropped without `finish` in [`OpenMode::Atomic`] removes its temporary files.
+    pub fn finish(mut self) -> Result<T> {
+        self.writer.finalize()?;
+        if let Some(index_writer) = self.index_writer.as_mut() {
+            let data = match self.output_files.first() {
+                Some(output_file) => DataInfo::from_metadata(&output_file.file.metadata()?),
+                None => DataInfo {
+                    size: self.position,
+                    ..Default::default()
+                },
+            };
+            index_writer.write_footer(DataInfo {
+                crc: self.data_crc,
+                ..data
+            })?;
+            index_writer.flush()?;
+        }
+        // data first, an index without its data is worse than a missing index
+        for output_file in self.output_files.drain(..) {
+            output_file.commit()?;
+        }
+        Ok(self.writer)
+    }
+}
+
 impl TfrecordWriter<BufWriter<File>> {
     pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = File::options().append(true).create(true).open(path)?;
-        let writer = BufWriter::new(file);
-        Ok(Self::new(writer))
+        Self::create_with_mode(path, OpenMode::default())
+    }
+
+    pub fn create_with_mode<P: AsRef<Path>>(path: P, mode: OpenMode) -> Result<Self> {
+        let output_file = OutputFile::open(path.as_ref(), mode)?;
+        let file = output_file.file.try_clone()?;
+        let position = file.metadata()?.len();
+        let mut writer = Self::new(BufWriter::new(file));
+        writer.position = position;
+        if position > 0 {
+            writer.data_crc = None;
+        }
+        writer.output_files.push(output_file);
+        Ok(writer)
+    }
+
+    /// Create the writer and its index, default index path is `<file>.tfrecord.idx`.
+    ///
+    /// In [`OpenMode::Append`] the footer of an existing index is dropped and rewritten by `finish`,
+    /// a missing index is rebuilt from the records already in the file.
+    pub fn create_with_index<P: AsRef<Path>, Q: AsRef<Path>>(
+        path: P,
+        index_path: Option<Q>,
+        mode: OpenMode,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let index_path = index_path
+            .map(|p| p.as_ref().to_owned())
+            .unwrap_or_else(|| path.with_extension("tfrecord.idx"));
+        // index first, so an existing index in `CreateNew` leaves no data file behind
+        let index_file = OutputFile::open(&index_path, mode)?;
+        let mut writer = match Self::create_with_mode(path, mode) {
+            Ok(writer) => writer,
+            Err(err) => {
+                if mode == OpenMode::CreateNew {
+                    let _ = std::fs::remove_file(&index_path);
+                }
+                return Err(err);
+            }
+        };
+
+        let mut entries = Vec::new();
+        if mode == OpenMode::Append {
+            File::open(&index_path)?.read_to_end(&mut entries)?;
+            let len = split_index(&entries)?.0.len();
+            entries.truncate(len);
+            index_file.file.set_len(len as u64)?;
+        }
+        let mut index_writer = SyncIndexWriter::with_state(
+            Box::new(BufWriter::new(index_file.file.try_clone()?)) as Box<dyn Write + Send>,
+            (entries.len() / ENTRY_SIZE) as u64,
+            crc32c::crc32c(&entries),
+        );
+        if entries.is_empty() && writer.position > 0 {
+            for entry in TfrecordReader::open(path, true)?.indices() {
+                let (offset, length) = entry?;
+                index_writer.write_index(offset, length)?;
+            }
+        }
+        writer.index_writer = Some(index_writer);
+        writer.output_files.push(index_file);
+        Ok(writer)
+    }
+}
+
+impl<T: Write> TfrecordWriter<Encoder<T>> {
+    pub fn with_compression(writer: T, compression: Compression) -> Self {
+        let mut writer = Self::new(Encoder::new(writer, compression));
+        if compression != Compression::None {
+            // the crc would cover the uncompressed stream, not the file
+            writer.data_crc = None;
+        }
+        writer
+    }
+}
+
+impl TfrecordWriter<Encoder<BufWriter<File>>> {
+    /// [`OpenMode::Append`] works with GZIP, which reads members one after another,
+    /// but not with ZLIB.
+    pub fn create_with_compression<P: AsRef<Path>>(
+        path: P,
+        compression: Compression,
+        mode: OpenMode,
+    ) -> Result<Self> {
+        if compression == Compression::Zlib && mode == OpenMode::Append {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                "a zlib stream can't be appended to",
+            )
+            .into());
+        }
+        let output_file = OutputFile::open(path.as_ref(), mode)?;
+        let writer = BufWriter::new(output_file.file.try_clone()?);
+        let mut writer = Self::with_compression(writer, compression);
+        writer.output_files.push(output_file);
+        Ok(writer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexing::{source::IndexSource, sync_reader::IndexReader};
+
+    #[test]
+    fn index_matches_data() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+
+        let mut writer =
+            TfrecordWriter::create_with_index(&path, None::<&Path>, OpenMode::Truncate).unwrap();
+        let written: Vec<(u64, u64)> = (0..10)
+            .map(|i| writer.write(&vec![i as u8; 100 + i]).unwrap())
+            .collect();
+        writer.finish().unwrap();
+
+        let expected: Vec<(u64, u64)> = TfrecordReader::open(&path, true)
+            .unwrap()
+            .indices()
+            .map(|index| index.unwrap())
+            .collect();
+        let index = IndexReader::open(path.with_extension("tfrecord.idx")).unwrap();
+        assert_eq!(written, expected);
+        assert_eq!(index.iter().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn open_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let count = |path: &Path| TfrecordReader::open(path, true).unwrap().count();
+
+        let mut writer = TfrecordWriter::create_with_mode(&path, OpenMode::CreateNew).unwrap();
+        writer.write(b"first").unwrap();
+        writer.finish().unwrap();
+        assert!(TfrecordWriter::create_with_mode(&path, OpenMode::CreateNew).is_err());
+
+        let mut writer = TfrecordWriter::create_with_mode(&path, OpenMode::Append).unwrap();
+        assert_eq!(writer.write(b"second").unwrap(), (21, 22));
+        writer.finish().unwrap();
+        assert_eq!(count(&path), 2);
+
+        let mut writer = TfrecordWriter::create(&path).unwrap();
+        writer.write(b"third").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(count(&path), 1);
+
+        let zlib_path = dir.path().join("data.tfrecord.zz");
+        let append = TfrecordWriter::create_with_compression(
+            &zlib_path,
+            Compression::Zlib,
+            OpenMode::Append,
+        );
+        assert!(append.is_err());
+        assert!(!zlib_path.exists());
+
+        // writers of the same file don't share a temporary file
+        let mut first = TfrecordWriter::create_with_mode(&path, OpenMode::Atomic).unwrap();
+        let mut second = TfrecordWriter::create_with_mode(&path, OpenMode::Atomic).unwrap();
+        first.write(b"first").unwrap();
+        second.write(b"second").unwrap();
+        second.finish().unwrap();
+        first.finish().unwrap();
+        assert_eq!(count(&path), 1);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn atomic_finish() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let index_path = path.with_extension("tfrecord.idx");
+
+        let mut writer =
+            TfrecordWriter::create_with_index(&path, None::<&Path>, OpenMode::Atomic).unwrap();
+        writer.write(b"record").unwrap();
+        writer.flush().unwrap();
+        assert!(!path.exists());
+        assert!(!index_path.exists());
+
+        writer.finish().unwrap();
+        assert_eq!(TfrecordReader::open(&path, true).unwrap().count(), 1);
+        assert_eq!(IndexReader::open(&index_path).unwrap().len(), 1);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn index_open_modes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let index_path = dir.path().join("data.idx");
+        let entries = || {
+            IndexReader::open(&index_path)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>()
+        };
+
+        // a missing index is rebuilt from the existing records
+        let mut writer = TfrecordWriter::create_with_mode(&path, OpenMode::CreateNew).unwrap();
+        writer.write(b"first").unwrap();
+        writer.finish().unwrap();
+        let mut writer =
+            TfrecordWriter::create_with_index(&path, Some(index_path.clone()), OpenMode::Append)
+                .unwrap();
+        writer.write(b"second").unwrap();
+        writer.finish().unwrap();
+        assert_eq!(entries(), vec![(0, 21), (21, 22)]);
+
+        // an existing index fails before the data file is created
+        let other_path = dir.path().join("other.tfrecord");
+        let create_new =
+            TfrecordWriter::create_with_index(&other_path, Some(&index_path), OpenMode::CreateNew);
+        assert!(create_new.is_err());
+        assert!(!other_path.exists());
+
+        // temporary files of a dropped writer are removed
+        let mut writer =
+            TfrecordWriter::create_with_index(&path, Some(&index_path), OpenMode::Atomic).unwrap();
+        writer.write(b"third").unwrap();
+        drop(writer);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+        assert_eq!(entries(), vec![(0, 21), (21, 22)]);
+    }
+
+    #[test]
+    fn index_footer() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.tfrecord");
+        let index_path = path.with_extension("tfrecord.idx");
+
+        let mut writer =
+            TfrecordWriter::create_with_index(&path, None::<&Path>, OpenMode::Truncate).unwrap();
+        writer.write(b"first").unwrap();
+        writer.finish().unwrap();
+        let footer = *IndexReader::open(&index_path).unwrap().footer().unwrap();
+        assert_eq!(footer.num_records, 1);
+        footer.verify_data_crc(File::open(&path).unwrap()).unwrap();
+
+        let mut writer =
+            TfrecordWriter::create_with_index(&path, None::<&Path>, OpenMode::Append).unwrap();
+        writer.write(b"second").unwrap();
+        writer.finish().unwrap();
+        let index = IndexReader::open(&index_path).unwrap();
+        assert_eq!(index.iter().collect::<Vec<_>>(), vec![(0, 21), (21, 22)]);
+        assert_eq!(index.footer().unwrap().data.crc, None);
+        index.check_data(&File::open(&path).unwrap()).unwrap();
+
+        File::options()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(b"garbage")
+            .unwrap();
+        assert!(matches!(
+            index.check_data(&File::open(&path).unwrap()),
+            Err(crate::error::Error::InvalidIndex(_))
+        ));
     }
 }
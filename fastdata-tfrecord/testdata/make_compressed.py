@@ -0,0 +1,17 @@
+"""Write the GZIP and ZLIB fixtures of `compression::tests` with TensorFlow."""
+import tensorflow as tf
+
+
+def main():
+    for compression_type, name in [
+        ("GZIP", "tensorflow.tfrecord.gz"),
+        ("ZLIB", "tensorflow.tfrecord.zz"),
+    ]:
+        with tf.io.TFRecordWriter(name, options=compression_type) as writer:
+            for i in range(50):
+                numbers = (str(i * j * 7919 % 100003) for j in range(i * 10))
+                writer.write(" ".join(numbers).encode())
+
+
+if __name__ == "__main__":
+    main()